@@ -1,7 +1,7 @@
 #!/usr/bin/env sh
 
 commands() {
-    RUST_LOG=hackathon=debug cargo test test_api -- --nocapture
+    RUST_LOG=hackathon=debug cargo test test_api -- --ignored --nocapture
     RUST_LOG=hackathon=debug cargo run -- --help
 
     RUST_LOG=hackathon=debug cargo run -- code 'write a hello world app in rust'
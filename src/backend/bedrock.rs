@@ -0,0 +1,552 @@
+use std::collections::{HashMap, HashSet};
+
+use aws_config::BehaviorVersion;
+use aws_sdk_bedrockruntime::{
+    types::{
+        ContentBlock, ContentBlockDelta, ContentBlockStart, ConversationRole, ConverseStreamOutput,
+        DocumentBlock, DocumentFormat, DocumentSource, ImageBlock, ImageFormat, ImageSource,
+        Message, StopReason, SystemContentBlock, Tool, ToolConfiguration, ToolInputSchema,
+        ToolResultBlock, ToolResultContentBlock, ToolResultStatus, ToolSpecification, ToolUseBlock,
+    },
+    Client,
+};
+use aws_smithy_types::{
+    error::{display::DisplayErrorContext, operation::BuildError},
+    Blob, Document, Number,
+};
+use serde_json::Value;
+use tracing::{debug, info};
+
+use crate::message::{self, ContentBlock as Block};
+use crate::tools::ToolBox;
+use crate::{
+    AiClient, ModelRequest, SendMessageError, SendMessageResponse, StorableMessage, TokenUsage,
+};
+
+const MODEL_ID: &str = "anthropic.claude-3-haiku-20240307-v1:0";
+const CLAUDE_REGION: &str = "us-west-2";
+
+/// The most model turns a single request may take when the model keeps calling tools.
+const MAX_TOOL_ROUNDS: usize = 10;
+
+#[derive(Debug)]
+pub struct BedrockClient {
+    client: Client,
+    model_id: String,
+}
+
+impl BedrockClient {
+    pub async fn new(model_id: Option<String>) -> Self {
+        let sdk_config = aws_config::defaults(BehaviorVersion::latest())
+            .region(CLAUDE_REGION)
+            .load()
+            .await;
+        let client = Client::new(&sdk_config);
+
+        Self {
+            client,
+            model_id: model_id.unwrap_or_else(|| MODEL_ID.to_string()),
+        }
+    }
+
+    fn messages(request: &ModelRequest) -> Result<Vec<Message>, BuildError> {
+        let mut document_names = HashSet::new();
+        request
+            .messages
+            .iter()
+            .map(|m| message(m, &mut document_names))
+            .collect()
+    }
+}
+
+fn message(
+    message: &StorableMessage,
+    document_names: &mut HashSet<String>,
+) -> Result<Message, BuildError> {
+    let content = message
+        .content
+        .iter()
+        .map(|block| content_block(block, document_names))
+        .collect::<Result<Vec<_>, _>>()?;
+    Message::builder()
+        .role(match message.role.as_str() {
+            "user" => ConversationRole::User,
+            _ => ConversationRole::Assistant,
+        })
+        .set_content(Some(content))
+        .build()
+}
+
+/// Converts `block`, naming documents so that none of them share a name in `document_names`.
+fn content_block(
+    block: &Block,
+    document_names: &mut HashSet<String>,
+) -> Result<ContentBlock, BuildError> {
+    Ok(match block {
+        Block::Image { format, data, .. } => ContentBlock::Image(
+            ImageBlock::builder()
+                .format(match format {
+                    message::ImageFormat::Png => ImageFormat::Png,
+                    message::ImageFormat::Jpeg => ImageFormat::Jpeg,
+                    message::ImageFormat::Gif => ImageFormat::Gif,
+                    message::ImageFormat::Webp => ImageFormat::Webp,
+                })
+                .source(ImageSource::Bytes(Blob::new(data.clone())))
+                .build()?,
+        ),
+        Block::Document { name, format, data } => ContentBlock::Document(
+            DocumentBlock::builder()
+                .format(match format {
+                    message::DocumentFormat::Pdf => DocumentFormat::Pdf,
+                    message::DocumentFormat::Csv => DocumentFormat::Csv,
+                    message::DocumentFormat::Doc => DocumentFormat::Doc,
+                    message::DocumentFormat::Docx => DocumentFormat::Docx,
+                    message::DocumentFormat::Xls => DocumentFormat::Xls,
+                    message::DocumentFormat::Xlsx => DocumentFormat::Xlsx,
+                })
+                .name(document_name(name, document_names))
+                .source(DocumentSource::Bytes(Blob::new(data.clone())))
+                .build()?,
+        ),
+        Block::ToolUse { id, name, input } => ContentBlock::ToolUse(
+            ToolUseBlock::builder()
+                .tool_use_id(id)
+                .name(name)
+                .input(to_document(input.clone()))
+                .build()?,
+        ),
+        Block::ToolResult {
+            tool_use_id,
+            text,
+            is_error,
+        } => ContentBlock::ToolResult(
+            ToolResultBlock::builder()
+                .tool_use_id(tool_use_id)
+                .content(ToolResultContentBlock::Text(text.clone()))
+                .status(if *is_error {
+                    ToolResultStatus::Error
+                } else {
+                    ToolResultStatus::Success
+                })
+                .build()?,
+        ),
+        block => ContentBlock::Text(block.to_text()),
+    })
+}
+
+/// The name of the document attached from `path`, as Bedrock only allows alphanumeric characters,
+/// single spaces, hyphens, parentheses and square brackets in it. Bedrock also rejects documents
+/// with the same name in one request, so a name already in `taken`, e.g. of `a/spec.pdf` when
+/// attaching `b/spec.pdf`, is numbered as `spec (2)`.
+fn document_name(path: &str, taken: &mut HashSet<String>) -> String {
+    let stem = std::path::Path::new(path)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy())
+        .unwrap_or_default();
+    let name = stem
+        .chars()
+        .map(|c| match c {
+            c if c.is_ascii_alphanumeric() || "-()[]".contains(c) => c,
+            c if c.is_whitespace() => ' ',
+            _ => '-',
+        })
+        .collect::<String>();
+    let mut base = name.split_whitespace().collect::<Vec<_>>().join(" ");
+    if base.is_empty() {
+        base = "document".to_string();
+    }
+    let mut name = base.clone();
+    for n in 2.. {
+        if taken.insert(name.clone()) {
+            break;
+        }
+        name = format!("{} ({})", base, n);
+    }
+    name
+}
+
+/// The response made of the text of the model's final turn, or `None` if it wrote none. Text it
+/// wrote before calling tools, e.g. "Let me look at that file", is left out, as it is not part of
+/// the answer.
+fn response(text: String, usage: Option<TokenUsage>) -> Option<SendMessageResponse> {
+    (!text.is_empty()).then_some(SendMessageResponse {
+        message: text,
+        usage,
+    })
+}
+
+fn no_text() -> SendMessageError {
+    SendMessageError::Custom("No text exists in the model response".into())
+}
+
+/// Returns the text delta carried by a stream event, if any.
+fn get_text(output: &ConverseStreamOutput) -> Option<&str> {
+    match output {
+        ConverseStreamOutput::ContentBlockDelta(ev) => {
+            ev.delta()?.as_text().ok().map(|t| t.as_str())
+        }
+        _ => None,
+    }
+}
+
+fn token_usage(usage: &aws_sdk_bedrockruntime::types::TokenUsage) -> TokenUsage {
+    TokenUsage {
+        input_tokens: usage.input_tokens().max(0) as u32,
+        output_tokens: usage.output_tokens().max(0) as u32,
+    }
+}
+
+fn add_usage(total: &mut Option<TokenUsage>, usage: Option<TokenUsage>) {
+    if let Some(usage) = usage {
+        *total.get_or_insert_with(TokenUsage::default) += usage;
+    }
+}
+
+fn tool_config(tools: &ToolBox) -> Result<ToolConfiguration, BuildError> {
+    let specs = tools
+        .specs()
+        .into_iter()
+        .map(|spec| {
+            Ok(Tool::ToolSpec(
+                ToolSpecification::builder()
+                    .name(spec.name)
+                    .description(spec.description)
+                    .input_schema(ToolInputSchema::Json(to_document(spec.input_schema)))
+                    .build()?,
+            ))
+        })
+        .collect::<Result<Vec<_>, BuildError>>()?;
+    ToolConfiguration::builder().set_tools(Some(specs)).build()
+}
+
+/// Runs every tool the model asked for in `content`, returning the user message carrying their
+/// results.
+async fn run_tools(tools: &ToolBox, content: &[ContentBlock]) -> Result<Message, BuildError> {
+    let mut results = Vec::new();
+    for tool_use in content.iter().filter_map(|c| c.as_tool_use().ok()) {
+        info!(
+            "Running tool {} with {:?}",
+            tool_use.name(),
+            tool_use.input()
+        );
+        let (text, status) = match tools
+            .run(tool_use.name(), from_document(tool_use.input()))
+            .await
+        {
+            Ok(text) => (text, ToolResultStatus::Success),
+            Err(e) => (e.to_string(), ToolResultStatus::Error),
+        };
+        results.push(ContentBlock::ToolResult(
+            ToolResultBlock::builder()
+                .tool_use_id(tool_use.tool_use_id())
+                .content(ToolResultContentBlock::Text(text))
+                .status(status)
+                .build()?,
+        ));
+    }
+    Message::builder()
+        .role(ConversationRole::User)
+        .set_content(Some(results))
+        .build()
+}
+
+fn to_document(value: Value) -> Document {
+    match value {
+        Value::Null => Document::Null,
+        Value::Bool(b) => Document::Bool(b),
+        Value::Number(n) => Document::Number(if let Some(n) = n.as_u64() {
+            Number::PosInt(n)
+        } else if let Some(n) = n.as_i64() {
+            Number::NegInt(n)
+        } else {
+            Number::Float(n.as_f64().unwrap_or_default())
+        }),
+        Value::String(s) => Document::String(s),
+        Value::Array(a) => Document::Array(a.into_iter().map(to_document).collect()),
+        Value::Object(o) => {
+            Document::Object(o.into_iter().map(|(k, v)| (k, to_document(v))).collect())
+        }
+    }
+}
+
+fn from_document(document: &Document) -> Value {
+    match document {
+        Document::Null => Value::Null,
+        Document::Bool(b) => Value::Bool(*b),
+        Document::Number(Number::PosInt(n)) => Value::from(*n),
+        Document::Number(Number::NegInt(n)) => Value::from(*n),
+        Document::Number(Number::Float(n)) => Value::from(*n),
+        Document::String(s) => Value::String(s.clone()),
+        Document::Array(a) => Value::Array(a.iter().map(from_document).collect()),
+        Document::Object(o) => Value::Object(
+            o.iter()
+                .map(|(k, v)| (k.clone(), from_document(v)))
+                .collect(),
+        ),
+    }
+}
+
+#[async_trait::async_trait]
+impl AiClient for BedrockClient {
+    fn model_id(&self) -> &str {
+        &self.model_id
+    }
+
+    async fn send_message(
+        &self,
+        request: ModelRequest,
+    ) -> Result<SendMessageResponse, SendMessageError> {
+        debug!("Sending request: {:?}", request);
+
+        let mut messages = Self::messages(&request)?;
+        let tool_config = request.tools.as_deref().map(tool_config).transpose()?;
+        let mut usage = None;
+
+        for _ in 0..MAX_TOOL_ROUNDS {
+            let res = self
+                .client
+                .converse()
+                .model_id(&self.model_id)
+                .system(SystemContentBlock::Text(request.system_prompt.clone()))
+                .set_messages(Some(messages.clone()))
+                .set_tool_config(tool_config.clone())
+                .send()
+                .await;
+
+            debug!("Received response: {:?}", res);
+
+            let res = match res {
+                Ok(res) => res,
+                Err(err) => {
+                    return match err {
+                        aws_smithy_runtime_api::client::result::SdkError::ServiceError(
+                            service_error,
+                        ) => Err(service_error.into_err().into()),
+                        err => Err(Box::new(err).into()),
+                    }
+                }
+            };
+            add_usage(&mut usage, res.usage().map(token_usage));
+
+            let message = res
+                .output()
+                .ok_or(SendMessageError::Custom("No output was received".into()))?
+                .as_message()
+                .map_err(|_| {
+                    SendMessageError::Custom("Unknown response received from the model".into())
+                })?;
+
+            match (res.stop_reason(), &request.tools) {
+                (StopReason::ToolUse, Some(tools)) => {
+                    let results = run_tools(tools, message.content()).await?;
+                    messages.push(message.clone());
+                    messages.push(results);
+                }
+                _ => {
+                    let text = message
+                        .content()
+                        .iter()
+                        .filter_map(|block| block.as_text().ok())
+                        .map(String::as_str)
+                        .collect();
+                    return response(text, usage).ok_or_else(no_text);
+                }
+            }
+        }
+
+        Err(SendMessageError::Custom(format!(
+            "The model was still calling tools after {} rounds",
+            MAX_TOOL_ROUNDS
+        )))
+    }
+
+    async fn send_message_stream(
+        &self,
+        request: ModelRequest,
+        on_delta: &mut (dyn for<'d> FnMut(&'d str) + Send),
+    ) -> Result<SendMessageResponse, SendMessageError> {
+        debug!("Sending streaming request: {:?}", request);
+
+        let mut messages = Self::messages(&request)?;
+        let tool_config = request.tools.as_deref().map(tool_config).transpose()?;
+        let mut usage = None;
+
+        for _ in 0..MAX_TOOL_ROUNDS {
+            let res = self
+                .client
+                .converse_stream()
+                .model_id(&self.model_id)
+                .system(SystemContentBlock::Text(request.system_prompt.clone()))
+                .set_messages(Some(messages.clone()))
+                .set_tool_config(tool_config.clone())
+                .send()
+                .await;
+
+            let mut stream = match res {
+                Ok(res) => res.stream,
+                Err(err) => {
+                    return match err {
+                        aws_smithy_runtime_api::client::result::SdkError::ServiceError(
+                            service_error,
+                        ) => Err(service_error.into_err().into()),
+                        err => Err(SendMessageError::Custom(
+                            DisplayErrorContext(&err).to_string(),
+                        )),
+                    }
+                }
+            };
+
+            // The content of this turn, so that it can be sent back along with any tool results.
+            let mut turn_text = String::new();
+            // Tool uses by content block index, with their input JSON as streamed so far.
+            let mut tool_uses: HashMap<i32, (String, String, String)> = HashMap::new();
+            let mut content = Vec::new();
+            let mut stop_reason = None;
+            loop {
+                match stream.recv().await {
+                    Ok(Some(output)) => {
+                        debug!("Got token: {:?}", output);
+                        if let Some(text) = get_text(&output) {
+                            // With tools, a turn may end up calling them, and what it wrote until
+                            // then is not part of the answer, so it is only forwarded once the
+                            // turn is known to be the last.
+                            if request.tools.is_none() {
+                                on_delta(text);
+                            }
+                            turn_text.push_str(text);
+                        }
+                        match &output {
+                            ConverseStreamOutput::ContentBlockStart(ev) => {
+                                if let Some(ContentBlockStart::ToolUse(start)) = ev.start() {
+                                    tool_uses.insert(
+                                        ev.content_block_index(),
+                                        (
+                                            start.tool_use_id().to_string(),
+                                            start.name().to_string(),
+                                            String::new(),
+                                        ),
+                                    );
+                                }
+                            }
+                            ConverseStreamOutput::ContentBlockDelta(ev) => {
+                                if let Some(ContentBlockDelta::ToolUse(delta)) = ev.delta() {
+                                    if let Some(tool_use) =
+                                        tool_uses.get_mut(&ev.content_block_index())
+                                    {
+                                        tool_use.2.push_str(delta.input());
+                                    }
+                                }
+                            }
+                            ConverseStreamOutput::ContentBlockStop(ev) => {
+                                if let Some((id, name, input)) =
+                                    tool_uses.remove(&ev.content_block_index())
+                                {
+                                    let input = serde_json::from_str(&input)
+                                        .unwrap_or(Value::Object(Default::default()));
+                                    content.push(ContentBlock::ToolUse(
+                                        ToolUseBlock::builder()
+                                            .tool_use_id(id)
+                                            .name(name)
+                                            .input(to_document(input))
+                                            .build()?,
+                                    ));
+                                }
+                            }
+                            ConverseStreamOutput::MessageStop(ev) => {
+                                stop_reason = Some(ev.stop_reason().clone());
+                            }
+                            ConverseStreamOutput::Metadata(metadata) => {
+                                add_usage(&mut usage, metadata.usage().map(token_usage));
+                            }
+                            _ => {}
+                        }
+                    }
+                    Ok(None) => break,
+                    Err(err) => {
+                        return match err {
+                            aws_smithy_runtime_api::client::result::SdkError::ServiceError(
+                                service_error,
+                            ) => Err(service_error.into_err().into()),
+                            err => Err(SendMessageError::Custom(
+                                DisplayErrorContext(&err).to_string(),
+                            )),
+                        }
+                    }
+                }
+            }
+
+            match (stop_reason, &request.tools) {
+                (Some(StopReason::ToolUse), Some(tools)) => {
+                    if !turn_text.is_empty() {
+                        content.insert(0, ContentBlock::Text(turn_text));
+                    }
+                    let results = run_tools(tools, &content).await?;
+                    messages.push(
+                        Message::builder()
+                            .role(ConversationRole::Assistant)
+                            .set_content(Some(content))
+                            .build()?,
+                    );
+                    messages.push(results);
+                }
+                _ => {
+                    if request.tools.is_some() && !turn_text.is_empty() {
+                        on_delta(&turn_text);
+                    }
+                    return response(turn_text, usage).ok_or_else(no_text);
+                }
+            }
+        }
+
+        Err(SendMessageError::Custom(format!(
+            "The model was still calling tools after {} rounds",
+            MAX_TOOL_ROUNDS
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_document_round_trip() {
+        let value = serde_json::json!({
+            "path": "src/main.rs",
+            "start_line": 1,
+            "offset": -2,
+            "ratio": 0.5,
+            "staged": true,
+            "paths": ["a", null],
+        });
+        assert_eq!(from_document(&to_document(value.clone())), value);
+    }
+
+    #[test]
+    fn test_document_name() {
+        let mut taken = HashSet::new();
+        let mut name = |path| document_name(path, &mut taken);
+        assert_eq!(name("specs/api_v2 (draft).pdf"), "api-v2 (draft)");
+        assert_eq!(name("a  b.xlsx"), "a b");
+        assert_eq!(name(""), "document");
+        assert_eq!(name("a/spec.pdf"), "spec");
+        assert_eq!(name("b/spec.pdf"), "spec (2)");
+        assert_eq!(name("c/spec.docx"), "spec (3)");
+    }
+
+    #[test]
+    fn test_image_block() {
+        let image = Block::Image {
+            name: "architecture.png".into(),
+            format: message::ImageFormat::Png,
+            data: vec![1, 2, 3],
+        };
+        let block = content_block(&image, &mut HashSet::new()).unwrap();
+        let image = block.as_image().unwrap();
+        assert_eq!(image.format(), &ImageFormat::Png);
+        assert_eq!(
+            image.source().unwrap().as_bytes().unwrap().as_ref(),
+            [1, 2, 3]
+        );
+    }
+}
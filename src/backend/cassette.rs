@@ -0,0 +1,258 @@
+use std::{
+    collections::BTreeSet,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use tracing::{debug, warn};
+
+use crate::{
+    AiClient, ModelRequest, SendMessageError, SendMessageResponse, StorableMessage, TokenUsage,
+};
+
+/// A single recorded request/response pair.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Interaction {
+    pub model_id: String,
+    pub system_prompt: String,
+    pub messages: Vec<StorableMessage>,
+    pub response: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usage: Option<TokenUsage>,
+}
+
+/// The on-disk file of recorded interactions.
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct Cassette {
+    pub interactions: Vec<Interaction>,
+}
+
+impl Cassette {
+    pub async fn load(path: &Path) -> anyhow::Result<Self> {
+        let json = tokio::fs::read_to_string(path).await?;
+        Ok(serde_json::from_str(&json)?)
+    }
+
+    pub async fn store(&self, path: &Path) -> anyhow::Result<()> {
+        let json = serde_json::to_string_pretty(self)?;
+        tokio::fs::write(path, json).await?;
+        Ok(())
+    }
+}
+
+/// Wraps another backend, appending every interaction with it to a cassette file.
+pub struct RecordingClient {
+    inner: Box<dyn AiClient>,
+    path: PathBuf,
+    cassette: Mutex<Cassette>,
+}
+
+impl RecordingClient {
+    /// Interactions are appended to the cassette at `path` if one already exists.
+    pub async fn new(inner: Box<dyn AiClient>, path: PathBuf) -> anyhow::Result<Self> {
+        let cassette = if path.is_file() {
+            Cassette::load(&path).await?
+        } else {
+            Cassette::default()
+        };
+        Ok(Self {
+            inner,
+            path,
+            cassette: Mutex::new(cassette),
+        })
+    }
+
+    async fn record(
+        &self,
+        request: ModelRequest,
+        response: &SendMessageResponse,
+    ) -> Result<(), SendMessageError> {
+        let mut cassette = self.cassette.lock().await;
+        cassette.interactions.push(Interaction {
+            model_id: self.inner.model_id().to_string(),
+            system_prompt: request.system_prompt,
+            messages: request.messages,
+            response: response.message.clone(),
+            usage: response.usage,
+        });
+        cassette.store(&self.path).await.map_err(|e| {
+            SendMessageError::Custom(format!(
+                "Unable to write cassette {}: {}",
+                self.path.display(),
+                e
+            ))
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl AiClient for RecordingClient {
+    fn model_id(&self) -> &str {
+        self.inner.model_id()
+    }
+
+    fn max_input_tokens(&self) -> usize {
+        self.inner.max_input_tokens()
+    }
+
+    async fn send_message(
+        &self,
+        request: ModelRequest,
+    ) -> Result<SendMessageResponse, SendMessageError> {
+        let response = self.inner.send_message(request.clone()).await?;
+        self.record(request, &response).await?;
+        Ok(response)
+    }
+
+    async fn send_message_stream(
+        &self,
+        request: ModelRequest,
+        on_delta: &mut (dyn for<'d> FnMut(&'d str) + Send),
+    ) -> Result<SendMessageResponse, SendMessageError> {
+        let response = self
+            .inner
+            .send_message_stream(request.clone(), on_delta)
+            .await?;
+        self.record(request, &response).await?;
+        Ok(response)
+    }
+
+    async fn list_models(&self) -> Result<Vec<String>, SendMessageError> {
+        self.inner.list_models().await
+    }
+}
+
+/// Answers requests from a cassette, without any network access.
+///
+/// Interactions are matched on the messages sent. The system prompt is only checked with a
+/// warning, so that tweaking the prompts does not invalidate every recorded cassette.
+#[derive(Debug)]
+pub struct ReplayClient {
+    cassette: Cassette,
+    model_id: String,
+}
+
+impl ReplayClient {
+    pub async fn new(path: &Path, model_id: Option<String>) -> anyhow::Result<Self> {
+        let cassette = Cassette::load(path)
+            .await
+            .map_err(|e| anyhow::anyhow!("Unable to load cassette {}: {}", path.display(), e))?;
+        Ok(Self::from_cassette(cassette, model_id))
+    }
+
+    pub fn from_cassette(cassette: Cassette, model_id: Option<String>) -> Self {
+        let model_id = model_id
+            .or_else(|| cassette.interactions.first().map(|i| i.model_id.clone()))
+            .unwrap_or_else(|| "replay".to_string());
+        Self { cassette, model_id }
+    }
+}
+
+#[async_trait::async_trait]
+impl AiClient for ReplayClient {
+    fn model_id(&self) -> &str {
+        &self.model_id
+    }
+
+    async fn send_message(
+        &self,
+        request: ModelRequest,
+    ) -> Result<SendMessageResponse, SendMessageError> {
+        let interaction = self
+            .cassette
+            .interactions
+            .iter()
+            .find(|i| i.messages == request.messages)
+            .ok_or_else(|| {
+                debug!("Unmatched request: {:?}", request);
+                SendMessageError::Custom(
+                    "No interaction in the cassette matches this request".into(),
+                )
+            })?;
+        if interaction.system_prompt != request.system_prompt {
+            warn!("Replaying an interaction recorded with a different system prompt");
+        }
+
+        Ok(SendMessageResponse {
+            message: interaction.response.clone(),
+            usage: interaction.usage,
+        })
+    }
+
+    async fn list_models(&self) -> Result<Vec<String>, SendMessageError> {
+        let models = self
+            .cassette
+            .interactions
+            .iter()
+            .map(|i| i.model_id.clone())
+            .collect::<BTreeSet<_>>();
+        Ok(models.into_iter().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct EchoClient;
+
+    #[async_trait::async_trait]
+    impl AiClient for EchoClient {
+        fn model_id(&self) -> &str {
+            "echo"
+        }
+
+        async fn send_message(
+            &self,
+            request: ModelRequest,
+        ) -> Result<SendMessageResponse, SendMessageError> {
+            Ok(SendMessageResponse {
+                message: request.messages.last().unwrap().text(),
+                usage: None,
+            })
+        }
+    }
+
+    fn request(prompt: &str) -> ModelRequest {
+        ModelRequest {
+            system_prompt: "You are Q".into(),
+            messages: vec![StorableMessage::new("user", prompt)],
+            tools: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_record_then_replay() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cassette.json");
+
+        let recorder = RecordingClient::new(Box::new(EchoClient), path.clone())
+            .await
+            .unwrap();
+        recorder.send_message(request("first")).await.unwrap();
+        recorder.send_message(request("second")).await.unwrap();
+
+        let replay = ReplayClient::new(&path, None).await.unwrap();
+        assert_eq!(replay.model_id(), "echo");
+        let res = replay.send_message(request("second")).await.unwrap();
+        assert_eq!(res.message, "second");
+        assert!(replay.send_message(request("third")).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_list_models() {
+        let interaction = |model_id: &str| Interaction {
+            model_id: model_id.into(),
+            system_prompt: String::new(),
+            messages: Vec::new(),
+            response: String::new(),
+            usage: None,
+        };
+        let cassette = Cassette {
+            interactions: vec![interaction("b"), interaction("a"), interaction("b")],
+        };
+        let replay = ReplayClient::from_cassette(cassette, None);
+        assert_eq!(replay.list_models().await.unwrap(), ["a", "b"]);
+    }
+}
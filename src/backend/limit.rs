@@ -0,0 +1,46 @@
+use crate::{AiClient, ModelRequest, SendMessageError, SendMessageResponse};
+
+/// Wraps another backend, overriding how many tokens a request to it may use.
+pub struct LimitedClient {
+    inner: Box<dyn AiClient>,
+    max_input_tokens: usize,
+}
+
+impl LimitedClient {
+    pub fn new(inner: Box<dyn AiClient>, max_input_tokens: usize) -> Self {
+        Self {
+            inner,
+            max_input_tokens,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl AiClient for LimitedClient {
+    fn model_id(&self) -> &str {
+        self.inner.model_id()
+    }
+
+    fn max_input_tokens(&self) -> usize {
+        self.max_input_tokens
+    }
+
+    async fn send_message(
+        &self,
+        request: ModelRequest,
+    ) -> Result<SendMessageResponse, SendMessageError> {
+        self.inner.send_message(request).await
+    }
+
+    async fn send_message_stream(
+        &self,
+        request: ModelRequest,
+        on_delta: &mut (dyn for<'d> FnMut(&'d str) + Send),
+    ) -> Result<SendMessageResponse, SendMessageError> {
+        self.inner.send_message_stream(request, on_delta).await
+    }
+
+    async fn list_models(&self) -> Result<Vec<String>, SendMessageError> {
+        self.inner.list_models().await
+    }
+}
@@ -0,0 +1,149 @@
+mod bedrock;
+mod cassette;
+mod limit;
+mod ollama;
+mod openai;
+
+pub use bedrock::BedrockClient;
+pub use cassette::{RecordingClient, ReplayClient};
+pub use limit::LimitedClient;
+pub use ollama::OllamaClient;
+pub use openai::OpenAiClient;
+
+use std::path::PathBuf;
+
+use anyhow::Context;
+use clap::{Args, ValueEnum};
+
+use crate::AiClient;
+
+/// The model providers that `AiClient` implementations exist for.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BackendKind {
+    /// Anthropic models hosted on AWS Bedrock.
+    #[default]
+    Bedrock,
+    /// Any server speaking the OpenAI `/v1/chat/completions` wire format.
+    #[value(name = "openai")]
+    OpenAi,
+    /// A local Ollama daemon.
+    Ollama,
+    /// Answers from a cassette recorded with `--record`, without any network access.
+    Replay,
+}
+
+#[derive(Args, Debug)]
+pub struct BackendArgs {
+    /// The model backend to send requests to.
+    #[arg(
+        long,
+        global = true,
+        env = "HACKATHON_BACKEND",
+        value_enum,
+        default_value_t = BackendKind::Bedrock
+    )]
+    pub backend: BackendKind,
+    /// The model to use, overriding the backend's default.
+    #[arg(long, global = true, env = "HACKATHON_MODEL")]
+    pub model: Option<String>,
+    /// The base URL of the server for HTTP backends, e.g. `http://localhost:8000/v1` or
+    /// `http://localhost:11434`.
+    #[arg(long, global = true, env = "HACKATHON_BASE_URL")]
+    pub base_url: Option<String>,
+    /// Records every request and response to this cassette file.
+    #[arg(long, global = true, value_name = "CASSETTE")]
+    pub record: Option<PathBuf>,
+    /// The cassette file to answer from when using the `replay` backend.
+    #[arg(long, global = true, env = "HACKATHON_CASSETTE")]
+    pub cassette: Option<PathBuf>,
+    /// The most tokens a request may use, overriding the model's known context window. Older
+    /// conversation turns are dropped and context files shrunk to stay within it.
+    #[arg(long, global = true, env = "HACKATHON_MAX_CONTEXT_TOKENS")]
+    pub max_context_tokens: Option<usize>,
+}
+
+impl BackendArgs {
+    /// Creates the client for the selected backend.
+    pub async fn build(&self) -> anyhow::Result<Box<dyn AiClient>> {
+        let client: Box<dyn AiClient> = match self.backend {
+            BackendKind::Bedrock => Box::new(BedrockClient::new(self.model.clone()).await),
+            BackendKind::OpenAi => {
+                Box::new(OpenAiClient::new(self.base_url.clone(), self.model.clone()))
+            }
+            BackendKind::Ollama => Box::new(OllamaClient::new(
+                self.base_url.clone(),
+                self.model.clone(),
+                self.max_context_tokens,
+            )),
+            BackendKind::Replay => {
+                let cassette = self
+                    .cassette
+                    .as_ref()
+                    .context("--cassette is required for the replay backend")?;
+                Box::new(ReplayClient::new(cassette, self.model.clone()).await?)
+            }
+        };
+        let client: Box<dyn AiClient> = match &self.record {
+            Some(path) => Box::new(RecordingClient::new(client, path.clone()).await?),
+            None => client,
+        };
+        match self.max_context_tokens {
+            Some(max_input_tokens) => Ok(Box::new(LimitedClient::new(client, max_input_tokens))),
+            None => Ok(client),
+        }
+    }
+}
+
+/// Helpers for testing HTTP backends against a local stand-in server.
+#[cfg(test)]
+pub(crate) mod testing {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+        task::JoinHandle,
+    };
+
+    /// Serves a single HTTP request on a random local port, responding with `body` as JSON.
+    ///
+    /// Returns the base URL of the server, and a handle resolving to the raw request received.
+    pub async fn serve_once(body: &'static str) -> (String, JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut received = Vec::new();
+            let mut buf = [0; 4096];
+            loop {
+                let n = stream.read(&mut buf).await.unwrap();
+                received.extend_from_slice(&buf[..n]);
+                if n == 0 || is_complete(&received) {
+                    break;
+                }
+            }
+            let response = format!(
+                "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            stream.write_all(response.as_bytes()).await.unwrap();
+            String::from_utf8_lossy(&received).to_string()
+        });
+        (format!("http://{}", addr), handle)
+    }
+
+    fn is_complete(received: &[u8]) -> bool {
+        let text = String::from_utf8_lossy(received);
+        let Some((headers, body)) = text.split_once("\r\n\r\n") else {
+            return false;
+        };
+        let content_length = headers
+            .lines()
+            .find_map(|line| {
+                let (name, value) = line.split_once(':')?;
+                name.eq_ignore_ascii_case("content-length")
+                    .then(|| value.trim().parse::<usize>().ok())?
+            })
+            .unwrap_or(0);
+        body.len() >= content_length
+    }
+}
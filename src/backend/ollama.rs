@@ -0,0 +1,232 @@
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use crate::tokens::RESERVED_OUTPUT_TOKENS;
+use crate::{AiClient, ModelRequest, SendMessageError, SendMessageResponse, TokenUsage};
+
+const DEFAULT_BASE_URL: &str = "http://localhost:11434";
+const DEFAULT_MODEL: &str = "llama3.2";
+/// The context window asked of Ollama unless told otherwise. Ollama's own default of 2048 or 4096
+/// tokens is too small for most context, while the full window of e.g. llama3 would not fit in
+/// the memory of most machines.
+const DEFAULT_CONTEXT_WINDOW: usize = 8192;
+
+/// A client for a local Ollama daemon, for working without network access.
+#[derive(Debug)]
+pub struct OllamaClient {
+    http: reqwest::Client,
+    base_url: String,
+    model_id: String,
+    /// Sent as `num_ctx`, as Ollama otherwise cuts requests short at its own default.
+    context_window: usize,
+}
+
+impl OllamaClient {
+    /// Falls back to `OLLAMA_HOST` (as used by the Ollama CLI) and then the default port when no
+    /// `base_url` is given. Ollama is asked for a context window that fits `max_input_tokens` and
+    /// the reply.
+    pub fn new(
+        base_url: Option<String>,
+        model_id: Option<String>,
+        max_input_tokens: Option<usize>,
+    ) -> Self {
+        let base_url = base_url
+            .or_else(|| std::env::var("OLLAMA_HOST").ok())
+            .unwrap_or_else(|| DEFAULT_BASE_URL.to_string());
+        let base_url = if base_url.contains("://") {
+            base_url
+        } else {
+            format!("http://{}", base_url)
+        };
+        Self {
+            http: reqwest::Client::new(),
+            base_url: base_url.trim_end_matches('/').to_string(),
+            model_id: model_id.unwrap_or_else(|| DEFAULT_MODEL.to_string()),
+            context_window: max_input_tokens.map_or(DEFAULT_CONTEXT_WINDOW, |tokens| {
+                tokens + RESERVED_OUTPUT_TOKENS
+            }),
+        }
+    }
+
+    async fn check_status(res: reqwest::Response) -> Result<reqwest::Response, SendMessageError> {
+        let status = res.status();
+        if status.is_success() {
+            return Ok(res);
+        }
+        let text = res.text().await.unwrap_or_default();
+        Err(SendMessageError::Custom(format!(
+            "Ollama responded with {}: {}",
+            status, text
+        )))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct ChatMessage {
+    role: String,
+    content: String,
+}
+
+#[derive(Serialize, Debug)]
+struct ChatRequest {
+    model: String,
+    messages: Vec<ChatMessage>,
+    stream: bool,
+    options: ChatOptions,
+}
+
+#[derive(Serialize, Debug)]
+struct ChatOptions {
+    num_ctx: usize,
+}
+
+#[derive(Deserialize, Debug)]
+struct ChatResponse {
+    message: ChatMessage,
+    prompt_eval_count: Option<u32>,
+    eval_count: Option<u32>,
+}
+
+#[derive(Deserialize, Debug)]
+struct TagsResponse {
+    models: Vec<LocalModel>,
+}
+
+#[derive(Deserialize, Debug)]
+struct LocalModel {
+    name: String,
+}
+
+impl ChatRequest {
+    fn new(model: &str, context_window: usize, request: ModelRequest) -> Self {
+        let mut messages = Vec::with_capacity(request.messages.len() + 1);
+        if !request.system_prompt.is_empty() {
+            messages.push(ChatMessage {
+                role: "system".to_string(),
+                content: request.system_prompt,
+            });
+        }
+        messages.extend(request.messages.into_iter().map(|m| ChatMessage {
+            content: m.text(),
+            role: m.role,
+        }));
+        Self {
+            model: model.to_string(),
+            messages,
+            stream: false,
+            options: ChatOptions {
+                num_ctx: context_window,
+            },
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl AiClient for OllamaClient {
+    fn model_id(&self) -> &str {
+        &self.model_id
+    }
+
+    fn max_input_tokens(&self) -> usize {
+        self.context_window.saturating_sub(RESERVED_OUTPUT_TOKENS)
+    }
+
+    async fn send_message(
+        &self,
+        request: ModelRequest,
+    ) -> Result<SendMessageResponse, SendMessageError> {
+        let body = ChatRequest::new(&self.model_id, self.context_window, request);
+        debug!("Sending request: {:?}", body);
+
+        let res = self
+            .http
+            .post(format!("{}/api/chat", self.base_url))
+            .json(&body)
+            .send()
+            .await
+            .map_err(|e| {
+                SendMessageError::Custom(format!(
+                    "Unable to reach Ollama at {}, is `ollama serve` running? {}",
+                    self.base_url, e
+                ))
+            })?;
+        let res = Self::check_status(res)
+            .await?
+            .json::<ChatResponse>()
+            .await?;
+        debug!("Received response: {:?}", res);
+
+        let usage = match (res.prompt_eval_count, res.eval_count) {
+            (Some(input_tokens), Some(output_tokens)) => Some(TokenUsage {
+                input_tokens,
+                output_tokens,
+            }),
+            _ => None,
+        };
+        Ok(SendMessageResponse {
+            message: res.message.content,
+            usage,
+        })
+    }
+
+    async fn list_models(&self) -> Result<Vec<String>, SendMessageError> {
+        let res = self
+            .http
+            .get(format!("{}/api/tags", self.base_url))
+            .send()
+            .await?;
+        let res = Self::check_status(res)
+            .await?
+            .json::<TagsResponse>()
+            .await?;
+        Ok(res.models.into_iter().map(|m| m.name).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{backend::testing::serve_once, StorableMessage};
+
+    #[tokio::test]
+    async fn test_send_message() {
+        let (base_url, server) = serve_once(
+            r#"{"model":"llama3.2","message":{"role":"assistant","content":"hello"},"done":true}"#,
+        )
+        .await;
+
+        let client = OllamaClient::new(Some(base_url), None, None);
+        assert_eq!(
+            client.max_input_tokens(),
+            DEFAULT_CONTEXT_WINDOW - RESERVED_OUTPUT_TOKENS
+        );
+        let res = client
+            .send_message(ModelRequest {
+                system_prompt: "You are Q".into(),
+                messages: vec![StorableMessage::new("user", "hi")],
+                tools: None,
+            })
+            .await
+            .unwrap();
+        assert_eq!(res.message, "hello");
+
+        let received = server.await.unwrap();
+        assert!(received.starts_with("POST /api/chat"));
+        assert!(received.contains(r#""stream":false"#));
+        assert!(received.contains(r#""options":{"num_ctx":8192}"#));
+    }
+
+    #[tokio::test]
+    async fn test_list_models() {
+        let (base_url, _server) = serve_once(
+            r#"{"models":[{"name":"llama3.2:latest","size":2019393189},{"name":"qwen2.5-coder:7b","size":4683087332}]}"#,
+        )
+        .await;
+
+        let client = OllamaClient::new(Some(base_url), None, None);
+        assert_eq!(
+            client.list_models().await.unwrap(),
+            vec!["llama3.2:latest", "qwen2.5-coder:7b"]
+        );
+    }
+}
@@ -0,0 +1,221 @@
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use crate::{AiClient, ModelRequest, SendMessageError, SendMessageResponse, TokenUsage};
+
+const DEFAULT_BASE_URL: &str = "https://api.openai.com/v1";
+const DEFAULT_MODEL: &str = "gpt-4o-mini";
+
+/// A client for any server implementing the OpenAI `/v1/chat/completions` API, e.g. vLLM,
+/// llama.cpp server or LM Studio.
+#[derive(Debug)]
+pub struct OpenAiClient {
+    http: reqwest::Client,
+    base_url: String,
+    api_key: Option<String>,
+    model_id: String,
+}
+
+impl OpenAiClient {
+    /// `base_url` should include the version prefix, e.g. `http://localhost:8000/v1`. The API key
+    /// is read from `OPENAI_API_KEY`, and is optional since most local servers do not need one.
+    pub fn new(base_url: Option<String>, model_id: Option<String>) -> Self {
+        Self {
+            http: reqwest::Client::new(),
+            base_url: base_url
+                .unwrap_or_else(|| DEFAULT_BASE_URL.to_string())
+                .trim_end_matches('/')
+                .to_string(),
+            api_key: std::env::var("OPENAI_API_KEY").ok(),
+            model_id: model_id.unwrap_or_else(|| DEFAULT_MODEL.to_string()),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct ChatMessage {
+    role: String,
+    /// Left out or null in replies that only call tools or were filtered.
+    content: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+struct ChatCompletionRequest {
+    model: String,
+    messages: Vec<ChatMessage>,
+    stream: bool,
+}
+
+#[derive(Deserialize, Debug)]
+struct ChatCompletionResponse {
+    choices: Vec<Choice>,
+    usage: Option<Usage>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Usage {
+    prompt_tokens: u32,
+    completion_tokens: u32,
+}
+
+#[derive(Deserialize, Debug)]
+struct Choice {
+    message: ChatMessage,
+}
+
+#[derive(Deserialize, Debug)]
+struct ModelList {
+    data: Vec<ModelObject>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ModelObject {
+    id: String,
+}
+
+impl ChatCompletionRequest {
+    fn new(model: &str, request: ModelRequest) -> Self {
+        let mut messages = Vec::with_capacity(request.messages.len() + 1);
+        if !request.system_prompt.is_empty() {
+            messages.push(ChatMessage {
+                role: "system".to_string(),
+                content: Some(request.system_prompt),
+            });
+        }
+        messages.extend(request.messages.into_iter().map(|m| ChatMessage {
+            content: Some(m.text()),
+            role: m.role,
+        }));
+        Self {
+            model: model.to_string(),
+            messages,
+            stream: false,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl AiClient for OpenAiClient {
+    fn model_id(&self) -> &str {
+        &self.model_id
+    }
+
+    async fn send_message(
+        &self,
+        request: ModelRequest,
+    ) -> Result<SendMessageResponse, SendMessageError> {
+        let body = ChatCompletionRequest::new(&self.model_id, request);
+        debug!("Sending request: {:?}", body);
+
+        let mut req = self
+            .http
+            .post(format!("{}/chat/completions", self.base_url))
+            .json(&body);
+        if let Some(api_key) = &self.api_key {
+            req = req.bearer_auth(api_key);
+        }
+        let res = req.send().await?;
+
+        let status = res.status();
+        if !status.is_success() {
+            let text = res.text().await.unwrap_or_default();
+            return Err(SendMessageError::Custom(format!(
+                "The server responded with {}: {}",
+                status, text
+            )));
+        }
+
+        let res = res.json::<ChatCompletionResponse>().await?;
+        debug!("Received response: {:?}", res);
+
+        let usage = res.usage.map(|u| TokenUsage {
+            input_tokens: u.prompt_tokens,
+            output_tokens: u.completion_tokens,
+        });
+        let message = res
+            .choices
+            .into_iter()
+            .next()
+            .ok_or(SendMessageError::Custom(
+                "No choices exist in the model response".into(),
+            ))?
+            .message;
+
+        Ok(SendMessageResponse {
+            message: message.content.ok_or(SendMessageError::Custom(
+                "No text exists in the model response".into(),
+            ))?,
+            usage,
+        })
+    }
+
+    async fn list_models(&self) -> Result<Vec<String>, SendMessageError> {
+        let mut req = self.http.get(format!("{}/models", self.base_url));
+        if let Some(api_key) = &self.api_key {
+            req = req.bearer_auth(api_key);
+        }
+        let res = req.send().await?.error_for_status()?;
+        let models = res.json::<ModelList>().await?;
+        Ok(models.data.into_iter().map(|m| m.id).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{backend::testing::serve_once, StorableMessage};
+
+    fn request() -> ModelRequest {
+        ModelRequest {
+            system_prompt: "You are Q".into(),
+            messages: vec![
+                StorableMessage::new("user", "hello"),
+                StorableMessage::new("assistant", "hi"),
+                StorableMessage::new("user", "write a hello world app"),
+            ],
+            tools: None,
+        }
+    }
+
+    #[test]
+    fn test_request_maps_system_prompt_and_history() {
+        let body = ChatCompletionRequest::new("local-model", request());
+        let roles = body
+            .messages
+            .iter()
+            .map(|m| m.role.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(roles, vec!["system", "user", "assistant", "user"]);
+        assert_eq!(body.messages[0].content.as_deref(), Some("You are Q"));
+        assert!(!body.stream);
+    }
+
+    #[tokio::test]
+    async fn test_send_message() {
+        let (base_url, server) = serve_once(
+            r#"{"choices":[{"index":0,"message":{"role":"assistant","content":"fn main() {}"}}]}"#,
+        )
+        .await;
+
+        let client = OpenAiClient::new(Some(base_url), Some("local-model".into()));
+        let res = client.send_message(request()).await.unwrap();
+        assert_eq!(res.message, "fn main() {}");
+
+        let received = server.await.unwrap();
+        assert!(received.starts_with("POST /chat/completions"));
+        assert!(received.contains(r#""model":"local-model""#));
+    }
+
+    #[tokio::test]
+    async fn test_send_message_without_content() {
+        let (base_url, server) = serve_once(
+            r#"{"choices":[{"index":0,"message":{"role":"assistant","content":null}}]}"#,
+        )
+        .await;
+
+        let client = OpenAiClient::new(Some(base_url), Some("local-model".into()));
+        let err = client.send_message(request()).await.unwrap_err();
+        assert_eq!(err.to_string(), "No text exists in the model response");
+        server.await.unwrap();
+    }
+}
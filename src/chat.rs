@@ -1,7 +1,14 @@
-use std::{path::Path, time::Instant};
+use std::{io::Write, path::Path, str::FromStr, sync::Arc, time::Instant};
 
+use chrono::Utc;
 use clap::Args;
+use tracing::warn;
 
+use crate::context::ContextArgs;
+use crate::output::{Event, EventKind, OutputFormat};
+use crate::store::{Conversation, StoreArgs};
+use crate::system_prompts::{COMPACT_PROMPT, SYSTEM_PROMPT};
+use crate::tokens;
 use crate::*;
 
 #[derive(Args, Debug)]
@@ -12,210 +19,449 @@ pub struct ChatArgs {
     resume_chat_ctx: String,
     #[arg(short, long)]
     current_repo_dir: String,
-    #[arg(short, long)]
-    file_ctx: Option<Vec<String>>,
+    #[command(flatten)]
+    context: ContextArgs,
+    /// How to print the response.
+    #[arg(long, value_enum, default_value_t)]
+    output: OutputFormat,
+    /// Let the model read, list and search files in `current_repo_dir` while answering. Only
+    /// supported by the bedrock backend.
+    #[arg(long)]
+    tools: bool,
+    /// Summarize the oldest turns of the conversation once it grows past this many tokens.
+    /// Defaults to half of the model's context budget.
+    #[arg(long, env = "HACKATHON_COMPACT_AFTER_TOKENS")]
+    compact_after_tokens: Option<usize>,
+    /// Start `resume_chat_ctx` as a copy of another conversation, given as `<id>[@turn]`, up to
+    /// and including that turn or all of it.
+    #[arg(long)]
+    fork_from: Option<ForkFrom>,
     #[arg(name = "PROMPT")]
     prompt: Vec<String>,
 }
 
-pub async fn execute_chat(args: ChatArgs) -> anyhow::Result<()> {
-    let prompt = args.prompt.join(" ");
+/// A conversation and optionally one of its turns, as passed to `--fork-from`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ForkFrom {
+    pub id: String,
+    pub turn: Option<usize>,
+}
 
-    let current_repo_dir = Path::new(&args.current_repo_dir);
-    if !current_repo_dir.is_dir() {
-        anyhow::bail!("current repo directory given is invalid");
-    }
-    let db_path = Path::join(current_repo_dir, ".db");
-    if !db_path.is_dir() {
-        tokio::fs::create_dir(Path::join(current_repo_dir, ".db")).await?;
+impl FromStr for ForkFrom {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((id, turn)) = s.rsplit_once('@') else {
+            return Ok(Self {
+                id: s.to_string(),
+                turn: None,
+            });
+        };
+        let turn = turn
+            .parse::<usize>()
+            .ok()
+            .filter(|turn| *turn > 0)
+            .ok_or_else(|| "expected <id>[@turn] with a positive turn".to_string())?;
+        Ok(Self {
+            id: id.to_string(),
+            turn: Some(turn),
+        })
     }
+}
+
+pub async fn execute_chat(
+    args: ChatArgs,
+    store: &StoreArgs,
+    client: &dyn AiClient,
+) -> anyhow::Result<()> {
+    let output = args.output;
+    output.start(EventKind::Chat, client.model_id())?;
+    output.finish(send_chat(args, store, client).await)
+}
 
-    let mut stdin = tokio::io::stdin();
-    let mut context = if std::io::stdin().is_terminal() {
-        String::new()
+async fn send_chat(args: ChatArgs, store: &StoreArgs, client: &dyn AiClient) -> anyhow::Result<()> {
+    let output = args.output;
+    let prompt = args.prompt.join(" ");
+
+    let attachments = args.context.read_attachments().await?;
+    let max_tokens = client.max_input_tokens().saturating_sub(
+        tokens::estimate(SYSTEM_PROMPT)
+            + tokens::estimate(&prompt)
+            + attachments
+                .iter()
+                .map(tokens::estimate_block)
+                .sum::<usize>(),
+    );
+    let context = args.context.read(max_tokens).await?;
+
+    info!("Context: {:?}", context);
+
+    let store = store.open(Path::new(&args.current_repo_dir)).await?;
+    let mut conversation = match &args.fork_from {
+        Some(fork_from) => {
+            Conversation::find(store, &fork_from.id)
+                .await?
+                .fork(&args.resume_chat_ctx, fork_from.turn)
+                .await?
+        }
+        None => Conversation::open(store, &args.resume_chat_ctx).await?,
+    };
+    conversation.compact_after_tokens = args.compact_after_tokens;
+    let tools = if args.tools {
+        Some(Arc::new(ToolBox::new(Path::new(&args.current_repo_dir))?))
     } else {
-        let mut buf = Vec::with_capacity(256);
-        stdin.read_to_end(&mut buf).await?;
-        String::from_utf8_lossy(&buf).to_string()
+        None
     };
-    let file_ctx = args.file_ctx;
-    if let Some(file_ctx) = file_ctx {
-        for ctx in file_ctx {
-            let path = Path::new(&ctx);
-            if path.is_file() {
-                let buf = tokio::fs::read_to_string(path).await;
-                if let Ok(buf) = buf {
-                    context.push_str(&buf);
-                } else {
-                    info!(
-                        "{} is not a file, skipping.",
-                        path.to_str().unwrap_or("bad path")
-                    );
+
+    let mut stdout = std::io::stdout();
+    let mut escape = StreamEscaper::default();
+    let response = send(
+        client,
+        &mut conversation,
+        Message {
+            prompt,
+            free_context: context,
+            attachments,
+        },
+        tools,
+        &mut |delta| {
+            // Printing is best effort, the full reply is still stored once complete.
+            match output {
+                OutputFormat::Text => {
+                    let _ = write!(stdout, "{}", escape.push(delta));
+                    let _ = stdout.flush();
                 }
+                OutputFormat::Ndjson => {
+                    let _ = Event::Delta { text: delta }.emit();
+                }
+            }
+        },
+    )
+    .await?;
+    match output {
+        OutputFormat::Text => println!("{}", escape.finish()),
+        OutputFormat::Ndjson => {
+            if let Some(usage) = response.usage {
+                Event::Usage(usage).emit()?;
             }
         }
     }
 
-    let resume_ctx_path = Path::join(Path::new(".db"), &args.resume_chat_ctx);
+    Ok(())
+}
 
-    info!("Context: {:?}", context);
+/// Sends `message` as the next turn of `conversation`, storing the model's reply. Only the final
+/// reply is stored, not the tool calls made along the way.
+///
+/// The conversation is locked against other processes until then, and compacted first if it has
+/// grown too long. Otherwise it is only updated once the reply is complete, so a failed or
+/// cancelled request leaves it untouched.
+pub async fn send(
+    client: &dyn AiClient,
+    conversation: &mut Conversation,
+    message: Message,
+    tools: Option<Arc<ToolBox>>,
+    on_delta: &mut (dyn for<'d> FnMut(&'d str) + Send),
+) -> anyhow::Result<SendMessageResponse> {
+    let _lock = conversation.lock().await?;
+    let max_tokens = conversation
+        .compact_after_tokens
+        .unwrap_or(client.max_input_tokens() / 2);
+    // The request can still go out without compacting, trimmed to fit instead.
+    match compact(client, conversation, max_tokens).await {
+        Ok(true) => conversation.store().await?,
+        Ok(false) => {}
+        Err(e) => warn!("Unable to compact the conversation: {:?}", e),
+    }
 
-    let conversation_history_path = Path::join(current_repo_dir, resume_ctx_path);
-    let client = BedrockClient::from_id(&conversation_history_path).await;
+    if conversation.metadata.title.is_none() {
+        conversation.metadata.title = Some(title(&message.prompt));
+    }
+    let user_message: StorableMessage = message.into();
+    let mut messages = conversation.messages().to_vec();
+    describe_attachments(&mut messages);
+    messages.push(user_message.clone());
+    // Times are kept for the record only, the model is not sent them.
+    for message in &mut messages {
+        message.created_at = None;
+    }
+    let mut request = ModelRequest {
+        system_prompt: SYSTEM_PROMPT.into(),
+        messages,
+        tools,
+    };
+    // Only the request is trimmed, the stored conversation keeps every turn.
+    let dropped = tokens::trim_history(&mut request, client.max_input_tokens());
+    if dropped > 0 {
+        info!(
+            "Dropped the {} oldest messages to fit the context window",
+            dropped
+        );
+    }
 
     let response = {
         let start = Instant::now();
-        let res = client
-            .send_message(Message {
-                prompt,
-                free_context: context,
-            })
-            .await?;
+        let res = client.send_message_stream(request, on_delta).await?;
         let end = Instant::now();
         debug!("Response took {} ms", (end - start).as_millis());
         res
     };
 
-    match response {
-        SendMessageResponse::Chat(chat) => println!("{}", chat),
-        SendMessageResponse::Code { language, code } => {
-            debug!("Model responded with language: {}", language);
-            println!("{}", code)
-        }
-    }
-
-    client.store_conversation().await?;
+    let cleaned_text = escape_newlines(&response.message);
+    let mut reply = StorableMessage::new("assistant", cleaned_text.clone());
+    reply.created_at = Some(Utc::now());
+    conversation.metadata.updated_at = Utc::now();
+    conversation.metadata.model = Some(client.model_id().to_string());
+    conversation.append(vec![user_message, reply]).await?;
 
-    Ok(())
+    Ok(SendMessageResponse {
+        message: cleaned_text,
+        usage: response.usage,
+    })
 }
 
-#[derive(Debug)]
-pub struct BedrockClient<'a> {
-    client: Client,
-    conversation_history: Arc<Mutex<Vec<StorableMessage>>>,
-    conversation_id: &'a Path,
+/// Escapes the literal `\n`s in a reply, which clients would otherwise take for newlines.
+pub fn escape_newlines(text: &str) -> String {
+    text.replace("\\n", "\\\\n")
 }
 
-unsafe impl Send for BedrockClient<'_> {}
+/// Undoes `escape_newlines`, giving back a stored reply as the model wrote it.
+pub fn unescape_newlines(text: &str) -> String {
+    text.replace("\\\\n", "\\n")
+}
 
-impl<'a> BedrockClient<'a> {
-    pub async fn from_id(convo_history_path: &'a Path) -> Self {
-        let sdk_config = aws_config::defaults(BehaviorVersion::latest())
-            .region(CLAUDE_REGION)
-            .load()
-            .await;
-        let client = Client::new(&sdk_config);
+/// Escapes a reply streamed in chunks the same way as `escape_newlines` does the whole reply,
+/// holding back a trailing backslash until the next chunk tells whether an `n` follows it.
+#[derive(Default)]
+struct StreamEscaper {
+    backslash: bool,
+}
 
-        if !convo_history_path.is_file() {
-            if let Err(e) = tokio::fs::File::create(convo_history_path).await {
-                panic!("Error creating convo history file: {}", e);
-            }
+impl StreamEscaper {
+    fn push(&mut self, delta: &str) -> String {
+        let mut text = if std::mem::take(&mut self.backslash) {
+            format!("\\{}", delta)
+        } else {
+            delta.to_string()
+        };
+        if text.ends_with('\\') {
+            text.pop();
+            self.backslash = true;
         }
+        escape_newlines(&text)
+    }
 
-        let bedrock_client = Self {
-            client,
-            conversation_history: Arc::new(Mutex::new(Vec::new())),
-            conversation_id: convo_history_path,
-        };
+    /// What is still held back once the reply is complete.
+    fn finish(self) -> &'static str {
+        if self.backslash {
+            "\\"
+        } else {
+            ""
+        }
+    }
+}
 
-        if let Ok(ctx_buf) = tokio::fs::read_to_string(convo_history_path).await {
-            if let Ok(mut previous_messages) =
-                serde_json::from_str::<Vec<StorableMessage>>(&ctx_buf)
-            {
-                let mut history = bedrock_client.conversation_history.lock().await;
-                history.append(&mut previous_messages);
-            }
+/// Replaces the images and documents in `messages` with their description, so that each is only
+/// sent along with the turn it was attached to instead of with every turn after it. This also
+/// keeps requests within Bedrock's limit on documents. The stored conversation keeps them.
+fn describe_attachments(messages: &mut [StorableMessage]) {
+    for block in messages.iter_mut().flat_map(|m| &mut m.content) {
+        if let ContentBlock::Image { .. } | ContentBlock::Document { .. } = block {
+            *block = ContentBlock::text(block.to_text());
         }
+    }
+}
 
-        bedrock_client
+/// Replaces the oldest turns of `conversation` with a summary written by the model once its
+/// messages add up to more than `max_tokens`, moving the turns themselves to its archive. The
+/// newest turns are kept as they are, up to half of `max_tokens`. Returns whether anything was
+/// compacted.
+pub async fn compact(
+    client: &dyn AiClient,
+    conversation: &mut Conversation,
+    max_tokens: usize,
+) -> anyhow::Result<bool> {
+    let messages = conversation.messages();
+    let total = messages.iter().map(tokens::estimate_message).sum::<usize>();
+    if total <= max_tokens {
+        return Ok(false);
     }
 
-    pub async fn store_conversation(&self) -> anyhow::Result<()> {
-        let json = serde_json::to_string_pretty(&*self.conversation_history.lock().await)?;
-        tokio::fs::write(self.conversation_id, json).await?;
-        Ok(())
+    // The kept turns must start with a user message, so that the summary can go before them.
+    let mut split = messages.len();
+    let mut kept = 0;
+    while split > 0 {
+        kept += tokens::estimate_message(&messages[split - 1]);
+        if kept > max_tokens / 2 {
+            break;
+        }
+        split -= 1;
+    }
+    while split < messages.len() && messages[split].role != "user" {
+        split += 1;
+    }
+    if split == 0 {
+        return Ok(false);
     }
 
-    // unused for now
-    #[allow(dead_code)]
-    pub async fn load_conversation<P: AsRef<Path>>(&mut self, path: P) -> anyhow::Result<()> {
-        let json = tokio::fs::read_to_string(path).await?;
-        let mut history = self.conversation_history.lock().await;
-        let new_history = serde_json::from_str::<Vec<StorableMessage>>(&json)?;
-        std::mem::take(&mut *history);
-        *history = new_history;
-        Ok(())
+    info!("Summarizing the {} oldest messages", split);
+    let transcript = messages[..split]
+        .iter()
+        .map(|m| format!("<{0}>\n{1}\n</{0}>\n", m.role, m.text()))
+        .collect::<String>();
+    let response = client
+        .send_message(ModelRequest {
+            system_prompt: COMPACT_PROMPT.into(),
+            messages: vec![StorableMessage::new(
+                "user",
+                format!("<transcript>\n{}</transcript>", transcript),
+            )],
+            tools: None,
+        })
+        .await?;
+
+    let summary = [
+        StorableMessage::new(
+            "user",
+            format!(
+                "<summary>\n{}\n</summary>\n\nThis summarizes our conversation so far.",
+                response.message
+            ),
+        ),
+        StorableMessage::new("assistant", "Understood, I will continue from there."),
+    ];
+    conversation.archive_oldest(split, summary);
+    Ok(true)
+}
+
+/// A title for a conversation started with `prompt`: its first line, cut short if needed.
+fn title(prompt: &str) -> String {
+    const MAX_TITLE_CHARS: usize = 60;
+    let line = prompt.lines().map(str::trim).find(|line| !line.is_empty());
+    let line = line.unwrap_or_default();
+    if line.chars().count() <= MAX_TITLE_CHARS {
+        return line.to_string();
     }
+    let mut title = line.chars().take(MAX_TITLE_CHARS).collect::<String>();
+    title.push_str("...");
+    title
 }
 
-#[async_trait::async_trait]
-impl AiClient for BedrockClient<'_> {
-    async fn send_message(
-        &self,
-        message: Message,
-    ) -> Result<SendMessageResponse, SendMessageError> {
-        let mut history = self.conversation_history.lock().await;
-        let mut prompt = String::new();
-        if !message.free_context.is_empty() {
-            prompt.push_str(&format!("{}\n", message.free_context));
-        }
-        prompt.push_str(&message.prompt);
-        history.push(StorableMessage {
-            role: "user".to_string(),
-            content: prompt,
-        });
-        let messages = {
-            history
-                .iter()
-                .filter_map(|m| {
-                    let anth_msg: AnthropicMessage = m.into();
-                    let br_msg: Result<aws_sdk_bedrockruntime::types::Message, _> =
-                        (&anth_msg).try_into();
-                    br_msg.ok()
-                })
-                .collect::<Vec<aws_sdk_bedrockruntime::types::Message>>()
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::ImageFormat;
+    use crate::store::JsonStore;
+
+    fn message(role: &str, content: &str) -> StorableMessage {
+        StorableMessage::new(role, content)
+    }
+
+    #[tokio::test]
+    async fn test_compact() {
+        let dir = tempfile::tempdir().unwrap();
+        let (question, answer) = ("q".repeat(400), "a".repeat(400));
+        let cassette = dir.path().join("cassette.json");
+        let transcript = format!(
+            "<transcript>\n<user>\n{}\n</user>\n<assistant>\n{}\n</assistant>\n</transcript>",
+            question, answer
+        );
+        let interactions = serde_json::json!({"interactions": [{
+            "model_id": "replay",
+            "system_prompt": "",
+            "messages": [message("user", &transcript)],
+            "response": "They asked about q.",
+        }]});
+        tokio::fs::write(&cassette, interactions.to_string())
+            .await
+            .unwrap();
+        let client = backend::ReplayClient::new(&cassette, None).await.unwrap();
+
+        let store = Arc::new(JsonStore::new(dir.path().to_path_buf()));
+        let mut conversation = Conversation::open(store.clone(), "1").await.unwrap();
+        conversation
+            .append(vec![
+                message("user", &question),
+                message("assistant", &answer),
+                message("user", "and r?"),
+                message("assistant", "r too."),
+            ])
+            .await
+            .unwrap();
+
+        assert!(!compact(&client, &mut conversation, 1000).await.unwrap());
+        assert!(compact(&client, &mut conversation, 150).await.unwrap());
+        conversation.store().await.unwrap();
+
+        let conversation = Conversation::find(store, "1").await.unwrap();
+        let messages = conversation.messages();
+        assert_eq!(messages.len(), 4);
+        assert!(messages[0].text().contains("They asked about q."));
+        assert_eq!(messages[1].role, "assistant");
+        assert_eq!(
+            messages[2..],
+            [message("user", "and r?"), message("assistant", "r too.")]
+        );
+        assert_eq!(
+            conversation.archive(),
+            [message("user", &question), message("assistant", &answer)]
+        );
+    }
+
+    #[test]
+    fn test_describe_attachments() {
+        let image = ContentBlock::Image {
+            name: "architecture.png".into(),
+            format: ImageFormat::Png,
+            data: b"\x89PNG\r\n\x1a\n".to_vec(),
         };
-        let res = self
-            .client
-            .converse()
-            .model_id(MODEL_ID)
-            .system(SystemContentBlock::Text(SYSTEM_PROMPT.into()))
-            .set_messages(Some(messages))
-            .send()
-            .await;
-
-        debug!("Received response: {:?}", res);
-
-        match res {
-            Ok(res) => {
-                let text = res
-                    .output()
-                    .ok_or(SendMessageError::Custom("No output was received".into()))?
-                    .as_message()
-                    .map_err(|_| {
-                        SendMessageError::Custom("Unknown response received from the model".into())
-                    })?
-                    .content()
-                    .first()
-                    .ok_or(SendMessageError::Custom(
-                        "No content exists in the model response".into(),
-                    ))?
-                    .as_text()
-                    .map_err(|_| SendMessageError::Custom("Model response was not text".into()))?;
-
-                let cleaned_text = text.replace("\\n", "\\\\n");
-                history.push(StorableMessage {
-                    role: "assistant".to_string(),
-                    content: cleaned_text.clone(),
-                });
-                Ok(SendMessageResponse::Chat(cleaned_text))
-            }
-            Err(err) => match err {
-                aws_smithy_runtime_api::client::result::SdkError::ServiceError(service_error) => {
-                    Err(service_error.into_err().into())
-                }
-                err => Err(err.into()),
-            },
+        let mut messages = vec![StorableMessage {
+            role: "user".into(),
+            content: vec![image, ContentBlock::text("what is this?")],
+            created_at: None,
+        }];
+        describe_attachments(&mut messages);
+        assert_eq!(
+            messages[0].text(),
+            "[image: architecture.png]\nwhat is this?"
+        );
+    }
+
+    #[test]
+    fn test_stream_escaper() {
+        let reply = "a\\nb\\\\nc\n\\";
+        for split in 0..=reply.len() {
+            let mut escape = StreamEscaper::default();
+            let (first, second) = reply.split_at(split);
+            let streamed = escape.push(first) + &escape.push(second) + escape.finish();
+            assert_eq!(streamed, escape_newlines(reply));
         }
+        assert_eq!(unescape_newlines(&escape_newlines(reply)), reply);
+    }
+
+    #[test]
+    fn test_fork_from() {
+        assert_eq!(
+            "1234@2".parse(),
+            Ok(ForkFrom {
+                id: "1234".into(),
+                turn: Some(2)
+            })
+        );
+        assert_eq!(
+            "1234".parse(),
+            Ok(ForkFrom {
+                id: "1234".into(),
+                turn: None
+            })
+        );
+        assert!("1234@0".parse::<ForkFrom>().is_err());
+        assert!("1234@last".parse::<ForkFrom>().is_err());
+    }
+
+    #[test]
+    fn test_title() {
+        assert_eq!(title("\n  what is a trie\nand more"), "what is a trie");
+        assert_eq!(title(&"x".repeat(61)), format!("{}...", "x".repeat(60)));
     }
 }
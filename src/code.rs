@@ -1,79 +1,236 @@
-use std::{iter::Peekable, path::Path, str::Lines};
-
-use anyhow::bail;
-use aws_sdk_bedrockruntime::{
-    error::SdkError,
-    operation::converse::ConverseError,
-    types::{ContentBlock, ConversationRole, Message as BedrockMessage, SystemContentBlock},
-    Client,
-};
+use std::{iter::Peekable, str::FromStr, str::Lines, time::Instant};
+
+use anyhow::Context;
 use clap::Args;
-use system_prompts::CODE_PROMPT;
+use system_prompts::{CODE_PROMPT, FOCUSED_CODE_PROMPT};
 
+use crate::context::{self, read_stdin, ContextArgs, ContextBlock};
+use crate::output::{Event, EventKind, OutputFormat};
+use crate::region::{self, LineRange};
 use crate::*;
 
 #[derive(Args, Debug)]
 pub struct CodeArgs {
-    #[arg(short, long)]
-    file_ctx: Option<Vec<String>>,
+    #[command(flatten)]
+    context: ContextArgs,
+    /// Where the user's cursor is, formatted as row,col[,file_path] with 1-based numbers. Only the
+    /// definition around the cursor is rewritten, in the file if given and stdin otherwise.
+    #[arg(short = 'p', long)]
+    cursor_position: Option<CursorPosition>,
+    /// How to print the response.
+    #[arg(long, value_enum, default_value_t)]
+    output: OutputFormat,
     #[arg(name = "PROMPT")]
     prompt: Vec<String>,
 }
 
-pub async fn execute_code(args: CodeArgs) -> anyhow::Result<()> {
+/// A cursor location, as passed to `--cursor-position`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CursorPosition {
+    pub row: usize,
+    pub col: usize,
+    pub file_path: Option<String>,
+}
+
+impl FromStr for CursorPosition {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(3, ',');
+        let mut number = |name| {
+            parts
+                .next()
+                .and_then(|n| n.trim().parse::<usize>().ok())
+                .filter(|n| *n > 0)
+                .ok_or_else(|| format!("expected row,col[,file_path] with a positive {}", name))
+        };
+        let row = number("row")?;
+        let col = number("col")?;
+        Ok(Self {
+            row,
+            col,
+            file_path: parts.next().map(str::to_string),
+        })
+    }
+}
+
+pub async fn execute_code(args: CodeArgs, client: &dyn AiClient) -> anyhow::Result<()> {
+    let output = args.output;
+    output.start(EventKind::Code, client.model_id())?;
+    output.finish(generate_code(args, client).await)
+}
+
+async fn generate_code(args: CodeArgs, client: &dyn AiClient) -> anyhow::Result<()> {
+    let output = args.output;
     let prompt = args.prompt.join(" ");
     debug!(prompt, "parsed prompt");
 
-    let mut stdin = tokio::io::stdin();
-    let mut free_context = if std::io::stdin().is_terminal() {
-        String::new()
-    } else {
-        let mut buf = Vec::with_capacity(256);
-        stdin.read_to_end(&mut buf).await?;
-        String::from_utf8_lossy(&buf).to_string()
+    let mut on_delta = |delta: &str| {
+        if output == OutputFormat::Ndjson {
+            let _ = Event::Delta { text: delta }.emit();
+        }
     };
-
-    debug!(free_context, "read free context from stdin");
-
-    let file_ctx = args.file_ctx;
-    if let Some(file_ctx) = file_ctx {
-        for ctx in file_ctx {
-            let path = Path::new(&ctx);
-            if path.is_file() {
-                let buf = tokio::fs::read_to_string(path).await;
-                if let Ok(buf) = buf {
-                    free_context.push_str(&buf);
-                } else {
-                    info!(
-                        "{} is not a file, skipping.",
-                        path.to_str().unwrap_or("bad path")
-                    );
+    let attachments = args.context.read_attachments().await?;
+    let attachment_tokens = attachments
+        .iter()
+        .map(tokens::estimate_block)
+        .sum::<usize>();
+    let (code_objects, usage) = match &args.cursor_position {
+        None => {
+            let max_tokens = client.max_input_tokens().saturating_sub(
+                tokens::estimate(CODE_PROMPT) + tokens::estimate(&prompt) + attachment_tokens,
+            );
+            let free_context = args.context.read(max_tokens).await?;
+            debug!(free_context, "read free context");
+            generate(client, &prompt, &free_context, attachments, &mut on_delta).await?
+        }
+        Some(cursor) => {
+            let stdin = read_stdin().await?;
+            let mut blocks = args.context.read_files().await?;
+            let buffer = match &cursor.file_path {
+                Some(path) => {
+                    blocks.splice(0..0, stdin);
+                    ContextBlock::File {
+                        path: path.clone(),
+                        content: tokio::fs::read_to_string(path)
+                            .await
+                            .with_context(|| format!("unable to read {}", path))?,
+                        lines: None,
+                    }
                 }
+                None => stdin.unwrap_or(ContextBlock::ActiveBuffer(String::new())),
+            };
+            // The buffer is sent whole, and its focused lines once more.
+            let max_tokens = client.max_input_tokens().saturating_sub(
+                tokens::estimate(FOCUSED_CODE_PROMPT)
+                    + tokens::estimate(&prompt)
+                    + 2 * tokens::estimate(buffer.content())
+                    + attachment_tokens,
+            );
+            context::shrink(&mut blocks, max_tokens);
+            let free_context = context::render(&blocks);
+            let (code_object, usage) = generate_focused(
+                client,
+                &prompt,
+                &free_context,
+                &buffer,
+                cursor,
+                attachments,
+                &mut on_delta,
+            )
+            .await?;
+            (vec![code_object], usage)
+        }
+    };
+    match output {
+        OutputFormat::Text => println!(
+            "{}",
+            serde_json::to_string(&CodeResponse::new(code_objects))?
+        ),
+        OutputFormat::Ndjson => {
+            for code_object in &code_objects {
+                Event::CodeBlock(code_object).emit()?;
+            }
+            if let Some(usage) = usage {
+                Event::Usage(usage).emit()?;
             }
         }
     }
 
-    let client = BedrockClient::new().await;
-    let response = {
-        let start = Instant::now();
-        let res = client
-            .send_message(format!("{}\n\n<prompt>{}</prompt>", free_context, prompt))
-            .await?;
-        let end = Instant::now();
-        debug!("Response took {} ms", (end - start).as_millis());
-        res
-    };
+    Ok(())
+}
+
+/// Asks the model to carry out `prompt` on `free_context` and `attachments`, returning the code
+/// blocks it replied with.
+pub async fn generate(
+    client: &dyn AiClient,
+    prompt: &str,
+    free_context: &str,
+    attachments: Vec<ContentBlock>,
+    on_delta: &mut (dyn for<'d> FnMut(&'d str) + Send),
+) -> anyhow::Result<(Vec<CodeObject>, Option<TokenUsage>)> {
+    let response = request(
+        client,
+        CODE_PROMPT,
+        attachments,
+        format!("{}\n\n<prompt>{}</prompt>", free_context, prompt),
+        on_delta,
+    )
+    .await?;
 
     let code_objects = ResponseParser::new(&response.message).parse()?;
-    println!(
-        "{}",
-        serde_json::to_string(&CodeResponse {
-            kind: Kind::Code,
-            message: code_objects
-        })?
-    );
+    Ok((code_objects, response.usage))
+}
 
-    Ok(())
+/// Asks the model to carry out `prompt` on only the definition around `cursor` in `buffer`,
+/// returning a single code block that replaces that range.
+pub async fn generate_focused(
+    client: &dyn AiClient,
+    prompt: &str,
+    free_context: &str,
+    buffer: &ContextBlock,
+    cursor: &CursorPosition,
+    attachments: Vec<ContentBlock>,
+    on_delta: &mut (dyn for<'d> FnMut(&'d str) + Send),
+) -> anyhow::Result<(CodeObject, Option<TokenUsage>)> {
+    let range = region::enclosing(buffer.content(), cursor.row);
+    debug!(?range, "focusing on the region around the cursor");
+    let response = request(
+        client,
+        FOCUSED_CODE_PROMPT,
+        attachments,
+        format!(
+            "{}{}\n<focus lines=\"{}-{}\">\n{}</focus>\n\n<prompt>{}</prompt>",
+            free_context,
+            buffer.render(),
+            range.start_line,
+            range.end_line,
+            range.slice(buffer.content()),
+            prompt
+        ),
+        on_delta,
+    )
+    .await?;
+
+    let mut code_object = ResponseParser::new(&response.message)
+        .parse()?
+        .into_iter()
+        .next()
+        .ok_or_else(|| SendMessageError::MalformedCode(response.message.clone()))?;
+    code_object.range = Some(range);
+    code_object.file_path = cursor.file_path.clone().or(code_object.file_path);
+    Ok((code_object, response.usage))
+}
+
+/// Sends `content` as a single user message, after `attachments`.
+async fn request(
+    client: &dyn AiClient,
+    system_prompt: &str,
+    attachments: Vec<ContentBlock>,
+    content: String,
+    on_delta: &mut (dyn for<'d> FnMut(&'d str) + Send),
+) -> anyhow::Result<SendMessageResponse> {
+    let start = Instant::now();
+    let res = client
+        .send_message_stream(
+            ModelRequest {
+                system_prompt: system_prompt.into(),
+                messages: vec![StorableMessage {
+                    role: "user".to_string(),
+                    content: attachments
+                        .into_iter()
+                        .chain([ContentBlock::text(content)])
+                        .collect(),
+                    created_at: None,
+                }],
+                tools: None,
+            },
+            on_delta,
+        )
+        .await?;
+    let end = Instant::now();
+    debug!("Response took {} ms", (end - start).as_millis());
+    Ok(res)
 }
 
 struct ResponseParser<'a> {
@@ -112,7 +269,16 @@ impl<'a> ResponseParser<'a> {
 
     fn parse_block(&mut self) -> anyhow::Result<CodeObject> {
         let markdown_start = self.iter.next().unwrap();
-        let language = markdown_start[3..].to_string();
+        // The info string is the language, optionally followed by the path of the file the code
+        // belongs to, e.g. "```rust path=src/lib.rs".
+        let info = &markdown_start[3..];
+        let (language, file_path) = match info.find("path=") {
+            Some(i) => (
+                info[..i].trim().to_string(),
+                Some(info[i + 5..].trim().trim_matches('"').to_string()),
+            ),
+            None => (info.trim().to_string(), None),
+        };
         let mut code = String::new();
         while let Some(line) = self.iter.peek() {
             if line.starts_with("```") {
@@ -120,92 +286,18 @@ impl<'a> ResponseParser<'a> {
                 break;
             }
             code.push_str(line);
-            code.push_str("\\n");
+            code.push('\n');
             self.next();
         }
         Ok(CodeObject {
             language,
             code,
-            file_path: None,
+            file_path,
+            range: None,
         })
     }
 }
 
-#[derive(Debug)]
-struct BedrockClient {
-    client: Client,
-}
-
-impl BedrockClient {
-    pub async fn new() -> Self {
-        let sdk_config = aws_config::defaults(BehaviorVersion::latest())
-            .region(CLAUDE_REGION)
-            .load()
-            .await;
-        let client = Client::new(&sdk_config);
-
-        Self { client }
-    }
-
-    pub async fn send_message(
-        &self,
-        message: String,
-    ) -> Result<SendMessageResponse, SendMessageError> {
-        debug!("Sending message: {:?}", message);
-
-        let res = self
-            .client
-            .converse()
-            .model_id(MODEL_ID)
-            .system(SystemContentBlock::Text(CODE_PROMPT.into()))
-            .messages(
-                BedrockMessage::builder()
-                    .role(ConversationRole::User)
-                    .content(ContentBlock::Text(message))
-                    .build()
-                    .unwrap(),
-            )
-            .send()
-            .await;
-
-        debug!("Received response: {:?}", res);
-
-        match res {
-            Ok(res) => {
-                let text = res
-                    .output()
-                    .ok_or(SendMessageError::Custom("No output was received".into()))?
-                    .as_message()
-                    .map_err(|_| {
-                        SendMessageError::Custom("Unknown response received from the model".into())
-                    })?
-                    .content()
-                    .first()
-                    .ok_or(SendMessageError::Custom(
-                        "No content exists in the model response".into(),
-                    ))?
-                    .as_text()
-                    .map_err(|_| SendMessageError::Custom("Model response was not text".into()))?;
-
-                Ok(SendMessageResponse {
-                    message: text.to_string(),
-                })
-            }
-            Err(err) => match err {
-                aws_smithy_runtime_api::client::result::SdkError::ServiceError(service_error) => {
-                    Err(service_error.into_err().into())
-                }
-                err => Err(err.into()),
-            },
-        }
-    }
-}
-
-#[derive(Debug)]
-struct SendMessageResponse {
-    message: String,
-}
-
 #[derive(Debug, Serialize)]
 enum Kind {
     #[serde(rename = "code")]
@@ -213,17 +305,39 @@ enum Kind {
 }
 
 #[derive(Debug, Serialize)]
-struct CodeResponse {
+pub struct CodeResponse {
     #[serde(rename = "type")]
     kind: Kind,
     message: Vec<CodeObject>,
 }
 
+impl CodeResponse {
+    pub fn new(message: Vec<CodeObject>) -> Self {
+        Self {
+            kind: Kind::Code,
+            message,
+        }
+    }
+}
+
 #[derive(Debug, Serialize)]
-struct CodeObject {
-    language: String,
-    code: String,
-    file_path: Option<String>,
+pub struct CodeObject {
+    pub language: String,
+    #[serde(serialize_with = "serialize_escaped_newlines")]
+    pub code: String,
+    pub file_path: Option<String>,
+    /// The lines of the file this code replaces, when only part of it was rewritten.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub range: Option<LineRange>,
+}
+
+/// Clients split `code` on a literal `\n` rather than on newlines, so newlines are escaped when
+/// serialized.
+fn serialize_escaped_newlines<S: serde::Serializer>(
+    code: &str,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&code.replace('\n', "\\n"))
 }
 
 #[cfg(test)]
@@ -238,6 +352,7 @@ mod tests {
                 language: "rust".into(),
                 code: "fn main() { println!(\"Hello, world!\"); }".into(),
                 file_path: None,
+                range: None,
             }],
         };
 
@@ -251,6 +366,27 @@ fn main() {
 }
 ```"#;
 
+    #[test]
+    fn test_cursor_position() {
+        assert_eq!(
+            "12,4".parse::<CursorPosition>(),
+            Ok(CursorPosition {
+                row: 12,
+                col: 4,
+                file_path: None,
+            })
+        );
+        assert_eq!(
+            "1,1,src/a,b.rs"
+                .parse::<CursorPosition>()
+                .unwrap()
+                .file_path,
+            Some("src/a,b.rs".to_string())
+        );
+        assert!("0,1".parse::<CursorPosition>().is_err());
+        assert!("12".parse::<CursorPosition>().is_err());
+    }
+
     #[test]
     fn test_parser() {
         let parser = ResponseParser::new(TEST_RESPONSE);
@@ -259,4 +395,23 @@ fn main() {
             serde_json::to_string(&parser.parse().unwrap()).unwrap()
         );
     }
+
+    #[test]
+    fn test_parser_file_path() {
+        let response = "```rust path=src/lib.rs\nfn a() {}\n```\n\n```python path=\"my scripts/b.py\"\npass\n```\n```\nplain\n```";
+        let code_objects = ResponseParser::new(response).parse().unwrap();
+        let paths = code_objects
+            .iter()
+            .map(|c| (c.language.as_str(), c.file_path.as_deref()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            paths,
+            vec![
+                ("rust", Some("src/lib.rs")),
+                ("python", Some("my scripts/b.py")),
+                ("", None)
+            ]
+        );
+        assert_eq!(code_objects[0].code, "fn a() {}\n");
+    }
 }
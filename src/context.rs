@@ -0,0 +1,587 @@
+//! Gathers the context sent along with a prompt from stdin, files and directories.
+//!
+//! Every piece of context is sent as its own tagged block, so that the model can tell where one
+//! file ends and the next begins, and which file it is asked to edit.
+
+use std::{io::IsTerminal, path::Path, str::FromStr};
+
+use clap::Args;
+use tokio::io::AsyncReadExt;
+use tracing::{info, warn};
+
+use crate::message::{ContentBlock, DocumentFormat, ImageFormat};
+use crate::region::{self, LineRange};
+use crate::tokens;
+
+/// Files larger than this are skipped when walking a directory.
+const MAX_FILE_BYTES: u64 = 256 * 1024;
+/// A directory stops being walked once this much of it has been added.
+const MAX_DIRECTORY_BYTES: usize = 2 * 1024 * 1024;
+/// How much of a file is checked for NUL bytes to decide whether it is binary.
+const BINARY_CHECK_BYTES: usize = 8 * 1024;
+/// The largest image Bedrock accepts.
+const MAX_IMAGE_BYTES: usize = 3_750_000;
+/// The largest document Bedrock accepts.
+const MAX_DOCUMENT_BYTES: usize = 4_500_000;
+/// The most documents Bedrock accepts in a single request.
+const MAX_DOCUMENTS: usize = 5;
+
+#[derive(Args, Debug)]
+pub struct ContextArgs {
+    /// Path to a file to use as context. Append `:start-end` to only send those lines, or
+    /// `#symbol` to only send the definitions of `symbol`. Images, PDFs, CSVs and Word or Excel
+    /// documents are attached as they are, which only the bedrock backend can see.
+    #[arg(short, long)]
+    file_ctx: Option<Vec<FileSpec>>,
+    /// Path to a directory to use as context. Files ignored by git, binary files and files larger
+    /// than 256KiB are skipped.
+    #[arg(short, long)]
+    directory_context: Option<Vec<String>>,
+}
+
+/// A `--file-ctx` argument, selecting all or part of a file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FileSpec {
+    pub path: String,
+    pub selection: Option<Selection>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Selection {
+    Lines(LineRange),
+    Symbol(String),
+}
+
+impl FromStr for FileSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let whole = |path: &str| FileSpec {
+            path: path.to_string(),
+            selection: None,
+        };
+        // Paths that happen to contain `:` or `#` are taken as is.
+        if Path::new(s).exists() {
+            return Ok(whole(s));
+        }
+        if let Some((path, symbol)) = s.rsplit_once('#') {
+            if symbol.is_empty() {
+                return Err(format!("expected a symbol after '#' in {}", s));
+            }
+            return Ok(FileSpec {
+                path: path.to_string(),
+                selection: Some(Selection::Symbol(symbol.to_string())),
+            });
+        }
+        let Some((path, lines)) = s.rsplit_once(':') else {
+            return Ok(whole(s));
+        };
+        let (start, end) = lines.split_once('-').unwrap_or((lines, lines));
+        let (Ok(start_line), Ok(end_line)) = (start.parse::<usize>(), end.parse::<usize>()) else {
+            return Ok(whole(s));
+        };
+        if start_line == 0 || end_line < start_line {
+            return Err(format!("invalid line range in {}", s));
+        }
+        Ok(FileSpec {
+            path: path.to_string(),
+            selection: Some(Selection::Lines(LineRange {
+                start_line,
+                end_line,
+            })),
+        })
+    }
+}
+
+/// A single piece of context.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ContextBlock {
+    /// The buffer the user is editing, piped through stdin.
+    ActiveBuffer(String),
+    File {
+        path: String,
+        content: String,
+        /// The lines of the file `content` was taken from, when it is not the whole file.
+        lines: Option<LineRange>,
+    },
+}
+
+impl ContextBlock {
+    pub fn content(&self) -> &str {
+        match self {
+            ContextBlock::ActiveBuffer(content) => content,
+            ContextBlock::File { content, .. } => content,
+        }
+    }
+
+    /// Renders the block as `<active_buffer>` or `<file path="...">` tags around its content.
+    pub fn render(&self) -> String {
+        let (open, close, content) = match self {
+            ContextBlock::ActiveBuffer(content) => {
+                ("<active_buffer>".to_string(), "</active_buffer>", content)
+            }
+            ContextBlock::File {
+                path,
+                content,
+                lines: None,
+            } => (format!("<file path=\"{}\">", path), "</file>", content),
+            ContextBlock::File {
+                path,
+                content,
+                lines: Some(lines),
+            } => (
+                format!(
+                    "<file path=\"{}\" lines=\"{}-{}\">",
+                    path, lines.start_line, lines.end_line
+                ),
+                "</file>",
+                content,
+            ),
+        };
+        format!(
+            "{}\n{}\n{}\n",
+            open,
+            content.strip_suffix('\n').unwrap_or(content),
+            close
+        )
+    }
+}
+
+/// Renders every block, in order.
+pub fn render(blocks: &[ContextBlock]) -> String {
+    blocks.iter().map(ContextBlock::render).collect()
+}
+
+/// Renders the code an editor or another agent sent along with a request as the active buffer,
+/// or nothing if it sent none.
+pub fn render_active_buffer(content: String) -> String {
+    if content.is_empty() {
+        return String::new();
+    }
+    render(&[ContextBlock::ActiveBuffer(content)])
+}
+
+impl ContextArgs {
+    /// Reads stdin, unless it is a terminal, followed by every file and directory given, shrinking
+    /// files as needed to fit in `max_tokens`.
+    pub async fn read(&self, max_tokens: usize) -> anyhow::Result<String> {
+        let mut blocks = Vec::new();
+        blocks.extend(read_stdin().await?);
+        let files = self.read_files().await?;
+        let any_files = !files.is_empty();
+        blocks.extend(files);
+        shrink(&mut blocks, max_tokens);
+        let is_file = |block: &ContextBlock| matches!(block, ContextBlock::File { .. });
+        if any_files && !blocks.iter().any(is_file) {
+            warn!(
+                "None of the files given fit in the {} tokens left for context",
+                max_tokens
+            );
+        }
+        Ok(render(&blocks))
+    }
+
+    /// Reads every file and directory given, other than attachments.
+    pub async fn read_files(&self) -> anyhow::Result<Vec<ContextBlock>> {
+        let mut blocks = Vec::new();
+        for spec in self.file_ctx.iter().flatten() {
+            let path = Path::new(&spec.path);
+            let data = match tokio::fs::read(path).await {
+                Ok(data) if path.is_file() => data,
+                _ => {
+                    info!("{} is not a file, skipping.", spec.path);
+                    continue;
+                }
+            };
+            // Attachments are read by `read_attachments` instead.
+            if ImageFormat::detect(&data).is_some() || DocumentFormat::detect(path, &data).is_some()
+            {
+                continue;
+            }
+            let Ok(content) = String::from_utf8(data) else {
+                info!("{} is not a text file, skipping.", spec.path);
+                continue;
+            };
+            blocks.extend(select(spec, content)?);
+        }
+
+        for dir in self.directory_context.iter().flatten() {
+            if !Path::new(dir).is_dir() {
+                anyhow::bail!("{} is not a directory", dir);
+            }
+            let dir = dir.clone();
+            blocks.extend(tokio::task::spawn_blocking(move || read_directory(&dir)).await??);
+        }
+
+        Ok(blocks)
+    }
+
+    /// Reads every image and document given with `--file-ctx`, to be attached to the prompt.
+    pub async fn read_attachments(&self) -> anyhow::Result<Vec<ContentBlock>> {
+        let mut attachments = Vec::new();
+        let mut documents = 0;
+        for spec in self.file_ctx.iter().flatten() {
+            let path = Path::new(&spec.path);
+            if !path.is_file() {
+                continue;
+            }
+            let data = tokio::fs::read(path).await?;
+            let (size, name) = (data.len(), spec.path.clone());
+            let (attachment, max_bytes) = if let Some(format) = ImageFormat::detect(&data) {
+                (ContentBlock::Image { name, format, data }, MAX_IMAGE_BYTES)
+            } else if let Some(format) = DocumentFormat::detect(path, &data) {
+                documents += 1;
+                let document = ContentBlock::Document { name, format, data };
+                (document, MAX_DOCUMENT_BYTES)
+            } else {
+                continue;
+            };
+            if spec.selection.is_some() {
+                anyhow::bail!(
+                    "{} is attached whole, so no part of it can be selected",
+                    spec.path
+                );
+            }
+            if size > max_bytes {
+                anyhow::bail!(
+                    "{} is {} bytes, larger than the {} bytes it may be",
+                    spec.path,
+                    size,
+                    max_bytes
+                );
+            }
+            attachments.push(attachment);
+        }
+        if documents > MAX_DOCUMENTS {
+            anyhow::bail!(
+                "{} documents were given, but at most {} can be attached",
+                documents,
+                MAX_DOCUMENTS
+            );
+        }
+        Ok(attachments)
+    }
+}
+
+/// Cuts lines from the end of the largest files in `blocks` until they fit in `max_tokens`,
+/// dropping files that would be left empty. The active buffer is kept whole, since it is usually
+/// what the request is about.
+pub fn shrink(blocks: &mut Vec<ContextBlock>, max_tokens: usize) {
+    loop {
+        let total = blocks
+            .iter()
+            .map(|b| tokens::estimate(&b.render()))
+            .sum::<usize>();
+        if total <= max_tokens {
+            return;
+        }
+        let Some(i) = (0..blocks.len())
+            .filter(|&i| matches!(blocks[i], ContextBlock::File { .. }))
+            .max_by_key(|&i| blocks[i].content().len())
+        else {
+            return;
+        };
+        let ContextBlock::File {
+            path,
+            content,
+            lines,
+        } = &mut blocks[i]
+        else {
+            unreachable!()
+        };
+
+        // Whole lines are cut, adding up to at least the excess.
+        let keep_chars = content
+            .chars()
+            .count()
+            .saturating_sub((total - max_tokens) * 4);
+        let mut kept_lines = 0;
+        let mut kept_chars = 0;
+        for line in content.lines() {
+            kept_chars += line.chars().count() + 1;
+            if kept_chars > keep_chars {
+                break;
+            }
+            kept_lines += 1;
+        }
+        if kept_lines == 0 {
+            info!("Dropping {} to fit in {} tokens.", path, max_tokens);
+            blocks.remove(i);
+            continue;
+        }
+        info!(
+            "Shrinking {} to {} lines to fit in {} tokens.",
+            path, kept_lines, max_tokens
+        );
+        let start_line = lines.map_or(1, |l| l.start_line);
+        *content = content
+            .lines()
+            .take(kept_lines)
+            .map(|line| format!("{}\n", line))
+            .collect();
+        *lines = Some(LineRange {
+            start_line,
+            end_line: start_line + kept_lines - 1,
+        });
+    }
+}
+
+/// Cuts the lines `spec` selects out of `content`.
+fn select(spec: &FileSpec, content: String) -> anyhow::Result<Vec<ContextBlock>> {
+    let ranges = match &spec.selection {
+        None => {
+            return Ok(vec![ContextBlock::File {
+                path: spec.path.clone(),
+                content,
+                lines: None,
+            }])
+        }
+        Some(Selection::Lines(range)) => {
+            let line_count = content.lines().count();
+            if range.start_line > line_count {
+                anyhow::bail!("{} only has {} lines", spec.path, line_count);
+            }
+            vec![LineRange {
+                start_line: range.start_line,
+                end_line: range.end_line.min(line_count),
+            }]
+        }
+        Some(Selection::Symbol(symbol)) => {
+            let ranges = region::definitions(&content, symbol);
+            if ranges.is_empty() {
+                anyhow::bail!("no definition of {} found in {}", symbol, spec.path);
+            }
+            ranges
+        }
+    };
+    Ok(ranges
+        .into_iter()
+        .map(|range| ContextBlock::File {
+            path: spec.path.clone(),
+            content: range.slice(&content),
+            lines: Some(range),
+        })
+        .collect())
+}
+
+/// Reads all of stdin as the active buffer, or nothing if it is a terminal or empty.
+pub async fn read_stdin() -> anyhow::Result<Option<ContextBlock>> {
+    if std::io::stdin().is_terminal() {
+        return Ok(None);
+    }
+    let mut buf = Vec::with_capacity(256);
+    tokio::io::stdin().read_to_end(&mut buf).await?;
+    if buf.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(ContextBlock::ActiveBuffer(
+        String::from_utf8_lossy(&buf).to_string(),
+    )))
+}
+
+/// Reads every text file under `dir`, in path order.
+fn read_directory(dir: &str) -> anyhow::Result<Vec<ContextBlock>> {
+    let mut blocks = Vec::new();
+    let mut size = 0;
+    let walker = ignore::WalkBuilder::new(dir)
+        .require_git(false)
+        .sort_by_file_name(|a, b| a.cmp(b))
+        .build();
+    for entry in walker {
+        let entry = entry?;
+        let path = entry.path();
+        if !entry.file_type().is_some_and(|t| t.is_file()) {
+            continue;
+        }
+        if entry.metadata()?.len() > MAX_FILE_BYTES {
+            info!("{} is too large, skipping.", path.display());
+            continue;
+        }
+        let bytes = std::fs::read(path)?;
+        if bytes[..bytes.len().min(BINARY_CHECK_BYTES)].contains(&0) {
+            info!("{} is binary, skipping.", path.display());
+            continue;
+        }
+        let Ok(text) = String::from_utf8(bytes) else {
+            info!("{} is not UTF-8, skipping.", path.display());
+            continue;
+        };
+        size += text.len();
+        if size > MAX_DIRECTORY_BYTES {
+            info!("{} exceeds the directory size limit, stopping.", dir);
+            break;
+        }
+        blocks.push(ContextBlock::File {
+            path: path.display().to_string(),
+            content: text,
+            lines: None,
+        });
+    }
+    Ok(blocks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_spec() {
+        let spec = |s: &str| s.parse::<FileSpec>();
+        assert_eq!(
+            spec("src/main.rs"),
+            Ok(FileSpec {
+                path: "src/main.rs".into(),
+                selection: None
+            })
+        );
+        assert_eq!(
+            spec("src/main.rs:120-180").unwrap().selection,
+            Some(Selection::Lines(LineRange {
+                start_line: 120,
+                end_line: 180
+            }))
+        );
+        assert_eq!(
+            spec("src/main.rs:7").unwrap().selection,
+            Some(Selection::Lines(LineRange {
+                start_line: 7,
+                end_line: 7
+            }))
+        );
+        assert_eq!(
+            spec("src/chat.rs#Conversation").unwrap(),
+            FileSpec {
+                path: "src/chat.rs".into(),
+                selection: Some(Selection::Symbol("Conversation".into()))
+            }
+        );
+        assert!(spec("src/main.rs:9-3").is_err());
+        assert!(spec("src/main.rs#").is_err());
+    }
+
+    #[test]
+    fn test_select() {
+        let content = "struct A;\n\nfn b() {\n    1\n}\n\nfn c() {}\n".to_string();
+        let select = |s: &str| select(&s.parse().unwrap(), content.clone()).map(|b| render(&b));
+        assert_eq!(
+            select("x.rs:2-4").unwrap(),
+            "<file path=\"x.rs\" lines=\"2-4\">\n\nfn b() {\n    1\n</file>\n"
+        );
+        assert_eq!(
+            select("x.rs#b").unwrap(),
+            "<file path=\"x.rs\" lines=\"3-5\">\nfn b() {\n    1\n}\n</file>\n"
+        );
+        // Ranges past the end are clamped to the file.
+        assert_eq!(
+            select("x.rs:7-100").unwrap(),
+            "<file path=\"x.rs\" lines=\"7-7\">\nfn c() {}\n</file>\n"
+        );
+        assert!(select("x.rs:8-9").is_err());
+        assert!(select("x.rs#d").is_err());
+    }
+
+    #[test]
+    fn test_shrink() {
+        let file = |path: &str, lines: usize| ContextBlock::File {
+            path: path.into(),
+            content: "0123456789abcde\n".repeat(lines),
+            lines: None,
+        };
+        let buffer = ContextBlock::ActiveBuffer("x".repeat(400));
+        let mut blocks = vec![buffer.clone(), file("small.rs", 10), file("big.rs", 100)];
+        let size = |blocks: &[ContextBlock]| tokens::estimate(&render(blocks));
+
+        shrink(&mut blocks, 1000);
+        assert_eq!(blocks.len(), 3);
+
+        shrink(&mut blocks, 400);
+        assert!(size(&blocks) <= 400);
+        assert_eq!(blocks[0], buffer);
+        assert_eq!(blocks[1], file("small.rs", 10));
+        let ContextBlock::File { lines, .. } = &blocks[2] else {
+            panic!("expected a file");
+        };
+        assert_eq!(lines.unwrap().start_line, 1);
+        assert!(lines.unwrap().end_line < 100);
+
+        // Files are dropped once nothing of them fits, but the active buffer never is.
+        shrink(&mut blocks, 1);
+        assert_eq!(blocks, vec![buffer]);
+    }
+
+    #[test]
+    fn test_read_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        std::fs::write(root.join(".gitignore"), "target/\n").unwrap();
+        std::fs::create_dir_all(root.join("src")).unwrap();
+        std::fs::create_dir_all(root.join("target")).unwrap();
+        std::fs::write(root.join("src/main.rs"), "fn main() {}\n").unwrap();
+        std::fs::write(root.join("src/lib.rs"), "pub fn lib() {}\n").unwrap();
+        std::fs::write(root.join("target/out.rs"), "fn ignored() {}\n").unwrap();
+        std::fs::write(root.join("logo.png"), [0x89, b'P', b'N', b'G', 0, 0]).unwrap();
+        std::fs::write(
+            root.join("big.txt"),
+            "a".repeat(MAX_FILE_BYTES as usize + 1),
+        )
+        .unwrap();
+
+        let context = render(&read_directory(root.to_str().unwrap()).unwrap());
+        let root = root.display();
+        assert_eq!(
+            context,
+            format!(
+                "<file path=\"{root}/src/lib.rs\">\npub fn lib() {{}}\n</file>\n\
+                 <file path=\"{root}/src/main.rs\">\nfn main() {{}}\n</file>\n"
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn test_read_attachments() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("architecture.png");
+        let spec = dir.path().join("api.pdf");
+        let text = dir.path().join("notes.txt");
+        let png = b"\x89PNG\r\n\x1a\n\0\0".to_vec();
+        std::fs::write(&image, &png).unwrap();
+        std::fs::write(&spec, "%PDF-1.7\n").unwrap();
+        std::fs::write(&text, "see the diagram\n").unwrap();
+        let image = image.to_str().unwrap();
+        let spec = spec.to_str().unwrap();
+        let text = text.to_str().unwrap();
+
+        let args = |specs: &[&str]| ContextArgs {
+            file_ctx: Some(specs.iter().map(|s| s.parse().unwrap()).collect()),
+            directory_context: None,
+        };
+        let context = args(&[image, spec, text]);
+        assert_eq!(
+            context.read_files().await.unwrap(),
+            [ContextBlock::File {
+                path: text.to_string(),
+                content: "see the diagram\n".into(),
+                lines: None,
+            }]
+        );
+        assert_eq!(
+            context.read_attachments().await.unwrap(),
+            [
+                ContentBlock::Image {
+                    name: image.to_string(),
+                    format: ImageFormat::Png,
+                    data: png,
+                },
+                ContentBlock::Document {
+                    name: spec.to_string(),
+                    format: DocumentFormat::Pdf,
+                    data: b"%PDF-1.7\n".to_vec(),
+                }
+            ]
+        );
+        let e = args(&[&format!("{}:1-2", image)]).read_attachments().await;
+        assert!(e.unwrap_err().to_string().contains("is attached whole"));
+        let e = args(&[spec; MAX_DOCUMENTS + 1]).read_attachments().await;
+        assert!(e.unwrap_err().to_string().contains("at most 5"));
+    }
+}
@@ -0,0 +1,339 @@
+//! `hackathon conversations`, for finding and managing the chats stored under `.db/`.
+
+use std::{collections::HashSet, path::PathBuf};
+
+use clap::{Args, Subcommand};
+use serde::Serialize;
+
+use crate::export::{export, ExportFormat};
+use crate::store::{Conversation, Metadata, StoreArgs};
+
+#[derive(Args, Debug)]
+pub struct ConversationsArgs {
+    /// The repository whose conversations to manage.
+    #[arg(short, long, default_value = ".")]
+    current_repo_dir: PathBuf,
+    #[command(subcommand)]
+    command: ConversationsCommand,
+}
+
+#[derive(Subcommand, Debug)]
+enum ConversationsCommand {
+    /// List conversations, most recently updated first.
+    List {
+        /// Print a JSON array instead of a table.
+        #[arg(long)]
+        json: bool,
+    },
+    /// Print the messages of a conversation.
+    Show { id: String },
+    /// Delete a conversation.
+    Delete { id: String },
+    /// Set the title of a conversation.
+    Rename {
+        id: String,
+        #[arg(name = "TITLE", required = true)]
+        title: Vec<String>,
+    },
+    /// Print a conversation, including its metadata and archived turns.
+    Export {
+        id: String,
+        #[arg(long, value_enum, default_value_t)]
+        format: ExportFormat,
+    },
+    /// Find the messages containing every word of a query, ignoring case.
+    Search {
+        /// Print a JSON array instead of one match per line.
+        #[arg(long)]
+        json: bool,
+        #[arg(name = "QUERY", required = true)]
+        query: Vec<String>,
+    },
+}
+
+/// How many characters of a message to show on either side of a match.
+const SNIPPET_CONTEXT_CHARS: usize = 40;
+
+/// A conversation as listed.
+#[derive(Serialize)]
+struct Summary<'a> {
+    id: &'a str,
+    #[serde(flatten)]
+    metadata: &'a Metadata,
+    message_count: usize,
+}
+
+/// A message matching a search.
+#[derive(Serialize)]
+struct Match<'a> {
+    id: &'a str,
+    title: Option<&'a str>,
+    /// The 1-based turn the message belongs to.
+    turn: usize,
+    role: &'a str,
+    /// Whether the message was archived when the conversation was compacted.
+    archived: bool,
+    snippet: String,
+}
+
+pub async fn execute_conversations(
+    args: ConversationsArgs,
+    store: &StoreArgs,
+) -> anyhow::Result<()> {
+    let store = store.open(&args.current_repo_dir).await?;
+    match args.command {
+        ConversationsCommand::List { json } => {
+            let mut conversations = Conversation::list(store).await?;
+            conversations.sort_by_key(|c| std::cmp::Reverse(c.metadata.updated_at));
+            if json {
+                let summaries = conversations.iter().map(summary).collect::<Vec<_>>();
+                println!("{}", serde_json::to_string(&summaries)?);
+            } else {
+                for conversation in &conversations {
+                    println!(
+                        "{:<12} {}  {:>4} messages  {}",
+                        conversation.id(),
+                        conversation.metadata.updated_at.format("%Y-%m-%d %H:%M"),
+                        conversation.messages().len(),
+                        conversation.metadata.title.as_deref().unwrap_or_default(),
+                    );
+                }
+            }
+        }
+        ConversationsCommand::Show { id } => {
+            let conversation = Conversation::find(store.clone(), &id).await?;
+            let metadata = &conversation.metadata;
+            println!("Title: {}", metadata.title.as_deref().unwrap_or_default());
+            println!("Created: {}", metadata.created_at.to_rfc3339());
+            println!("Updated: {}", metadata.updated_at.to_rfc3339());
+            println!("Model: {}", metadata.model.as_deref().unwrap_or_default());
+            if let Some(fork) = &metadata.forked_from {
+                println!("Forked from: {} at turn {}", fork.id, fork.turn);
+            }
+            if let Some(tree) = fork_tree(&Conversation::list(store).await?, &id) {
+                print!("\nForks:\n{}", tree);
+            }
+            for message in conversation.messages() {
+                println!("\n{}:\n{}", message.role, message.text());
+            }
+        }
+        ConversationsCommand::Delete { id } => {
+            let mut conversation = Conversation::find(store, &id).await?;
+            let _lock = conversation.lock().await?;
+            conversation.delete().await?;
+        }
+        ConversationsCommand::Rename { id, title } => {
+            let mut conversation = Conversation::find(store, &id).await?;
+            let _lock = conversation.lock().await?;
+            conversation.metadata.title = Some(title.join(" "));
+            conversation.store_metadata().await?;
+        }
+        ConversationsCommand::Export { id, format } => {
+            let conversation = Conversation::find(store, &id).await?;
+            println!("{}", export(&conversation, format)?.trim_end());
+        }
+        ConversationsCommand::Search { json, query } => {
+            let terms = query
+                .iter()
+                .flat_map(|q| q.split_whitespace())
+                .map(str::to_lowercase)
+                .collect::<Vec<_>>();
+            let mut conversations = Conversation::list(store).await?;
+            conversations.sort_by_key(|c| std::cmp::Reverse(c.metadata.updated_at));
+            let matches = conversations
+                .iter()
+                .flat_map(|c| search(c, &terms))
+                .collect::<Vec<_>>();
+            if json {
+                println!("{}", serde_json::to_string(&matches)?);
+            } else {
+                for m in &matches {
+                    let archived = if m.archived { " (archived)" } else { "" };
+                    println!(
+                        "{:<12} turn {:<3} {:<9} {}{}",
+                        m.id, m.turn, m.role, m.snippet, archived
+                    );
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Finds the messages of `conversation`, archived or not, that contain all of `terms`, which are
+/// lowercase.
+fn search<'a>(conversation: &'a Conversation, terms: &[String]) -> Vec<Match<'a>> {
+    let mut matches = Vec::new();
+    for (archived, messages) in [
+        (true, conversation.archive()),
+        (false, conversation.messages()),
+    ] {
+        let mut turn = 0;
+        for message in messages {
+            if message.role == "user" {
+                turn += 1;
+            }
+            let text = message.text();
+            let positions = terms
+                .iter()
+                .map(|term| find_ignoring_case(&text, term))
+                .collect::<Option<Vec<_>>>();
+            let Some(first) = positions.and_then(|p| p.into_iter().min()) else {
+                continue;
+            };
+            matches.push(Match {
+                id: conversation.id(),
+                title: conversation.metadata.title.as_deref(),
+                turn: turn.max(1),
+                role: &message.role,
+                archived,
+                snippet: snippet(&text, first),
+            });
+        }
+    }
+    matches
+}
+
+/// The byte offset of the first occurrence of `term`, which is lowercase, in `text`.
+fn find_ignoring_case(text: &str, term: &str) -> Option<usize> {
+    text.char_indices().map(|(i, _)| i).find(|&i| {
+        let mut rest = text[i..].chars().flat_map(char::to_lowercase);
+        term.chars().all(|c| rest.next() == Some(c))
+    })
+}
+
+/// The text around byte offset `at` of `text`, on a single line: some context before it, and
+/// twice as much from it on.
+fn snippet(text: &str, at: usize) -> String {
+    let before = text[..at].chars().rev().take(SNIPPET_CONTEXT_CHARS).count();
+    let start = text[..at].chars().count() - before;
+    let chars = text.chars().count();
+    let end = (start + before + 2 * SNIPPET_CONTEXT_CHARS).min(chars);
+
+    let mut snippet = String::new();
+    if start > 0 {
+        snippet.push_str("...");
+    }
+    let middle = text
+        .chars()
+        .skip(start)
+        .take(end - start)
+        .collect::<String>();
+    snippet.push_str(&middle.split_whitespace().collect::<Vec<_>>().join(" "));
+    if end < chars {
+        snippet.push_str("...");
+    }
+    snippet
+}
+
+fn summary(conversation: &Conversation) -> Summary<'_> {
+    Summary {
+        id: conversation.id(),
+        metadata: &conversation.metadata,
+        message_count: conversation.messages().len(),
+    }
+}
+
+/// Renders the tree of forks that conversation `id` belongs to, from its oldest ancestor that
+/// still exists, marking `id` itself. Returns nothing if it has never been forked.
+fn fork_tree(conversations: &[Conversation], id: &str) -> Option<String> {
+    let find = |id: &str| conversations.iter().find(|c| c.id() == id);
+    let mut root = find(id)?;
+    let mut seen = HashSet::from([root.id()]);
+    while let Some(parent) = root.metadata.forked_from.as_ref().and_then(|f| find(&f.id)) {
+        if !seen.insert(parent.id()) {
+            break;
+        }
+        root = parent;
+    }
+
+    let mut tree = String::new();
+    render_fork(
+        conversations,
+        root,
+        id,
+        "",
+        "",
+        &mut HashSet::new(),
+        &mut tree,
+    );
+    (tree.lines().count() > 1).then_some(tree)
+}
+
+fn render_fork<'a>(
+    conversations: &'a [Conversation],
+    conversation: &'a Conversation,
+    marked: &str,
+    prefix: &str,
+    child_prefix: &str,
+    seen: &mut HashSet<&'a str>,
+    tree: &mut String,
+) {
+    if !seen.insert(conversation.id()) {
+        return;
+    }
+    tree.push_str(prefix);
+    tree.push_str(conversation.id());
+    if let Some(fork) = &conversation.metadata.forked_from {
+        tree.push_str(&format!(" (turn {})", fork.turn));
+    }
+    if let Some(title) = &conversation.metadata.title {
+        tree.push_str(&format!("  {}", title));
+    }
+    if conversation.id() == marked {
+        tree.push_str("  *");
+    }
+    tree.push('\n');
+
+    let mut children = conversations
+        .iter()
+        .filter(|c| {
+            c.metadata
+                .forked_from
+                .as_ref()
+                .is_some_and(|f| f.id == conversation.id())
+        })
+        .collect::<Vec<_>>();
+    children.sort_by_key(|c| c.metadata.created_at);
+    for (i, child) in children.iter().enumerate() {
+        let (branch, indent) = if i + 1 == children.len() {
+            ("└── ", "    ")
+        } else {
+            ("├── ", "│   ")
+        };
+        render_fork(
+            conversations,
+            child,
+            marked,
+            &format!("{}{}", child_prefix, branch),
+            &format!("{}{}", child_prefix, indent),
+            seen,
+            tree,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_ignoring_case() {
+        assert_eq!(
+            find_ignoring_case("The Lifetime error", "lifetime"),
+            Some(4)
+        );
+        assert_eq!(find_ignoring_case("naïve LIFETIME", "lifetime"), Some(7));
+        assert_eq!(find_ignoring_case("lifetim", "lifetime"), None);
+    }
+
+    #[test]
+    fn test_snippet() {
+        assert_eq!(snippet("a short\nreply", 2), "a short reply");
+        let text = format!("{}needle{}", "x".repeat(100), "y".repeat(100));
+        assert_eq!(
+            snippet(&text, 100),
+            format!("...{}needle{}...", "x".repeat(40), "y".repeat(74))
+        );
+    }
+}
@@ -0,0 +1,366 @@
+//! Renders a stored conversation for use outside of `hackathon`, e.g. pasted into a design
+//! review or a pull request.
+
+use std::fmt::Write;
+
+use clap::ValueEnum;
+use serde::Serialize;
+
+use crate::chat;
+use crate::message::SCHEMA_VERSION;
+use crate::store::{Conversation, Metadata};
+use crate::StorableMessage;
+
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// The metadata and messages as stored, including archived turns.
+    #[default]
+    Json,
+    /// A Markdown document, with a section per message.
+    Markdown,
+    /// A standalone HTML page, with a section per message.
+    Html,
+}
+
+#[derive(Serialize)]
+struct Export<'a> {
+    /// The version of the message format, see `message::SCHEMA_VERSION`.
+    version: u32,
+    id: &'a str,
+    metadata: &'a Metadata,
+    messages: &'a [StorableMessage],
+    archive: &'a [StorableMessage],
+}
+
+/// A piece of a message: either prose or a fenced code block.
+#[derive(Debug, PartialEq, Eq)]
+enum Block<'a> {
+    Text(String),
+    Code {
+        /// What follows the opening fence, e.g. "rust path=src/lib.rs".
+        info: &'a str,
+        code: String,
+        fence: &'a str,
+        /// Whether the block was closed before the end of the message.
+        closed: bool,
+    },
+}
+
+pub fn export(conversation: &Conversation, format: ExportFormat) -> anyhow::Result<String> {
+    Ok(match format {
+        ExportFormat::Json => serde_json::to_string_pretty(&Export {
+            version: SCHEMA_VERSION,
+            id: conversation.id(),
+            metadata: &conversation.metadata,
+            messages: conversation.messages(),
+            archive: conversation.archive(),
+        })?,
+        ExportFormat::Markdown => markdown(conversation),
+        ExportFormat::Html => html(conversation),
+    })
+}
+
+/// The archived turns, which were summarized when the conversation was compacted, and then its
+/// messages, each paired with whether it was archived.
+fn all_messages(conversation: &Conversation) -> impl Iterator<Item = (&StorableMessage, bool)> {
+    let archive = conversation.archive().iter().map(|m| (m, true));
+    archive.chain(conversation.messages().iter().map(|m| (m, false)))
+}
+
+fn title(conversation: &Conversation) -> String {
+    match &conversation.metadata.title {
+        Some(title) => title.clone(),
+        None => format!("Conversation {}", conversation.id()),
+    }
+}
+
+/// The details shown at the top of an export, as label and value.
+fn details(conversation: &Conversation) -> Vec<(&'static str, String)> {
+    let metadata = &conversation.metadata;
+    let mut details = vec![
+        ("Conversation", conversation.id().to_string()),
+        ("Created", metadata.created_at.to_rfc3339()),
+        ("Updated", metadata.updated_at.to_rfc3339()),
+    ];
+    if let Some(model) = &metadata.model {
+        details.push(("Model", model.clone()));
+    }
+    if let Some(fork) = &metadata.forked_from {
+        details.push(("Forked from", format!("{} at turn {}", fork.id, fork.turn)));
+    }
+    details
+}
+
+/// The text of `message` as it was written. Replies are stored with their literal `\n`s escaped for
+/// clients, see `chat::escape_newlines`, which would change the code in them.
+fn text(message: &StorableMessage) -> String {
+    let text = message.text();
+    if message.role == "assistant" {
+        chat::unescape_newlines(&text)
+    } else {
+        text
+    }
+}
+
+/// "user" as "User".
+fn role_name(role: &str) -> String {
+    let mut chars = role.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+fn markdown(conversation: &Conversation) -> String {
+    let mut doc = format!("# {}\n\n", title(conversation));
+    for (label, value) in details(conversation) {
+        let _ = writeln!(doc, "- **{}:** {}", label, value);
+    }
+    for (message, archived) in all_messages(conversation) {
+        let archived = if archived { " (archived)" } else { "" };
+        let _ = write!(doc, "\n## {}{}\n\n", role_name(&message.role), archived);
+        if let Some(at) = &message.created_at {
+            let _ = write!(doc, "*{}*\n\n", at.to_rfc3339());
+        }
+        let text = text(message);
+        doc.push_str(text.trim_end());
+        // A reply cut off inside a code block would otherwise swallow the rest of the document.
+        if let Some(Block::Code {
+            fence,
+            closed: false,
+            ..
+        }) = blocks(&text).last()
+        {
+            let _ = write!(doc, "\n{}", fence);
+        }
+        doc.push('\n');
+    }
+    doc
+}
+
+fn html(conversation: &Conversation) -> String {
+    let title = escape(&title(conversation));
+    let mut doc = format!(
+        "<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">\n\
+         <title>{title}</title>\n<style>{STYLE}</style>\n</head>\n<body>\n\
+         <header>\n<h1>{title}</h1>\n<dl>\n"
+    );
+    let metadata = &conversation.metadata;
+    for (label, value) in details(conversation) {
+        let value = match label {
+            "Created" => time(&metadata.created_at),
+            "Updated" => time(&metadata.updated_at),
+            _ => escape(&value),
+        };
+        let _ = writeln!(doc, "<dt>{}</dt><dd>{}</dd>", label, value);
+    }
+    doc.push_str("</dl>\n</header>\n");
+
+    for (message, archived) in all_messages(conversation) {
+        let role = escape(&message.role);
+        let (class, label) = if archived {
+            (" archived", " <small>(archived)</small>")
+        } else {
+            ("", "")
+        };
+        let _ = write!(
+            doc,
+            "<section class=\"message {}{}\">\n<h2>{}{}</h2>\n",
+            role,
+            class,
+            escape(&role_name(&message.role)),
+            label
+        );
+        if let Some(at) = &message.created_at {
+            let _ = writeln!(doc, "<p class=\"sent\">{}</p>", time(at));
+        }
+        for block in blocks(&text(message)) {
+            match block {
+                Block::Text(text) => {
+                    for paragraph in text.split("\n\n").map(str::trim) {
+                        if !paragraph.is_empty() {
+                            let _ = writeln!(doc, "<p>{}</p>", inline(paragraph));
+                        }
+                    }
+                }
+                Block::Code { info, code, .. } => {
+                    let language = info.split_whitespace().next().unwrap_or_default();
+                    let class = if language.is_empty() {
+                        String::new()
+                    } else {
+                        format!(" class=\"language-{}\"", escape(language))
+                    };
+                    let _ = writeln!(doc, "<pre><code{}>{}</code></pre>", class, escape(&code));
+                }
+            }
+        }
+        doc.push_str("</section>\n");
+    }
+    doc.push_str("</body>\n</html>\n");
+    doc
+}
+
+const STYLE: &str = "body{font-family:sans-serif;max-width:50rem;margin:2rem auto;padding:0 1rem;\
+line-height:1.5}dt{font-weight:bold;float:left;clear:left;margin-right:.5rem}dt::after{content:\":\"}\
+.message{border-top:1px solid #ddd}.archived,.sent{color:#666}pre{background:#f6f8fa;padding:.75rem;\
+overflow-x:auto}code{font-family:monospace}";
+
+fn time(at: &chrono::DateTime<chrono::Utc>) -> String {
+    let at = at.to_rfc3339();
+    format!("<time datetime=\"{0}\">{0}</time>", at)
+}
+
+/// Splits `content` into prose and fenced code blocks. A block left open runs to the end.
+fn blocks(content: &str) -> Vec<Block<'_>> {
+    let mut blocks = Vec::new();
+    let mut text = String::new();
+    let mut lines = content.lines();
+    while let Some(line) = lines.next() {
+        let trimmed = line.trim_start();
+        let fence_len = trimmed.chars().take_while(|&c| c == '`').count();
+        if fence_len < 3 {
+            text.push_str(line);
+            text.push('\n');
+            continue;
+        }
+        if !text.is_empty() {
+            blocks.push(Block::Text(std::mem::take(&mut text)));
+        }
+        let (fence, info) = trimmed.split_at(fence_len);
+        let mut code = String::new();
+        let mut closed = false;
+        for line in lines.by_ref() {
+            let line_trimmed = line.trim();
+            if line_trimmed.starts_with(fence) && line_trimmed.chars().all(|c| c == '`') {
+                closed = true;
+                break;
+            }
+            code.push_str(line);
+            code.push('\n');
+        }
+        blocks.push(Block::Code {
+            info: info.trim(),
+            code,
+            fence,
+            closed,
+        });
+    }
+    if !text.is_empty() {
+        blocks.push(Block::Text(text));
+    }
+    blocks
+}
+
+/// Escapes `text` for use in HTML, in either text or attribute values.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// A paragraph of prose as HTML, keeping its line breaks and `inline code`.
+fn inline(paragraph: &str) -> String {
+    paragraph
+        .split('`')
+        .enumerate()
+        .map(|(i, part)| {
+            // Every other part is between backticks, unless the last one is left unclosed.
+            if i % 2 == 1 && paragraph.matches('`').count() > i {
+                format!("<code>{}</code>", escape(part))
+            } else if i % 2 == 1 {
+                format!("`{}", escape(part))
+            } else {
+                escape(part)
+            }
+        })
+        .collect::<String>()
+        .replace('\n', "<br>\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::store::JsonStore;
+
+    fn message(role: &str, content: &str) -> StorableMessage {
+        StorableMessage::new(role, content)
+    }
+
+    async fn conversation(dir: &std::path::Path) -> Conversation {
+        let store = Arc::new(JsonStore::new(dir.to_path_buf()));
+        let mut conversation = Conversation::open(store, "1").await.unwrap();
+        conversation.metadata.title = Some("Tries <in Rust>".into());
+        let mut question = message("user", "how do I `insert`?");
+        question.created_at = Some("2026-03-01T12:00:00Z".parse().unwrap());
+        conversation
+            .append(vec![
+                question,
+                // Stored the way `chat` stores replies.
+                message(
+                    "assistant",
+                    &chat::escape_newlines(
+                        "Like so:\n\n```rust path=src/trie.rs\nif a < b {}\nlet s = \"a\\nb\";\n",
+                    ),
+                ),
+            ])
+            .await
+            .unwrap();
+        conversation
+    }
+
+    #[test]
+    fn test_blocks() {
+        assert_eq!(
+            blocks("a\n````md\n```\nb\n````\nc"),
+            [
+                Block::Text("a\n".into()),
+                Block::Code {
+                    info: "md",
+                    code: "```\nb\n".into(),
+                    fence: "````",
+                    closed: true
+                },
+                Block::Text("c\n".into()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_markdown() {
+        let dir = tempfile::tempdir().unwrap();
+        let markdown = markdown(&conversation(dir.path()).await);
+        assert!(markdown.starts_with("# Tries <in Rust>\n\n- **Conversation:** 1\n"));
+        assert!(markdown.ends_with(
+            "## User\n\n*2026-03-01T12:00:00+00:00*\n\nhow do I `insert`?\n\n\
+             ## Assistant\n\nLike so:\n\n```rust path=src/trie.rs\nif a < b {}\n\
+             let s = \"a\\nb\";\n```\n"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_html() {
+        let dir = tempfile::tempdir().unwrap();
+        let html = html(&conversation(dir.path()).await);
+        assert!(html.contains("<h1>Tries &lt;in Rust&gt;</h1>"));
+        assert!(html.contains(
+            "<p class=\"sent\"><time datetime=\"2026-03-01T12:00:00+00:00\">2026-03-01T12:00:00+00:00\
+             </time></p>\n<p>how do I <code>insert</code>?</p>"
+        ));
+        assert!(html.contains(
+            "<pre><code class=\"language-rust\">if a &lt; b {}\n\
+             let s = &quot;a\\nb&quot;;\n</code></pre>"
+        ));
+        assert!(html.contains("<dt>Created</dt><dd><time datetime=\""));
+    }
+}
@@ -0,0 +1,87 @@
+//! JSON-RPC 2.0 messages, framed as one JSON object per line.
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+pub const INTERNAL_ERROR: i64 = -32603;
+/// Borrowed from LSP, for requests cancelled by the client.
+pub const REQUEST_CANCELLED: i64 = -32800;
+
+/// A request, or a notification when `id` is absent.
+#[derive(Deserialize, Debug)]
+pub struct Request {
+    #[allow(dead_code)]
+    pub jsonrpc: String,
+    pub id: Option<Value>,
+    pub method: String,
+    #[serde(default)]
+    pub params: Value,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Response {
+    jsonrpc: &'static str,
+    id: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<ErrorObject>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ErrorObject {
+    pub code: i64,
+    pub message: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Notification {
+    jsonrpc: &'static str,
+    method: String,
+    params: Value,
+}
+
+impl Response {
+    pub fn result(id: Value, result: Value) -> Self {
+        Self {
+            jsonrpc: "2.0",
+            id,
+            result: Some(result),
+            error: None,
+        }
+    }
+
+    pub fn error(id: Value, code: i64, message: impl Into<String>) -> Self {
+        Self {
+            jsonrpc: "2.0",
+            id,
+            result: None,
+            error: Some(ErrorObject {
+                code,
+                message: message.into(),
+            }),
+        }
+    }
+}
+
+impl Notification {
+    pub fn new(method: impl Into<String>, params: Value) -> Self {
+        Self {
+            jsonrpc: "2.0",
+            method: method.into(),
+            params,
+        }
+    }
+}
+
+/// Deserializes the params of a request, mapping failures to an `INVALID_PARAMS` error.
+pub fn params<T: serde::de::DeserializeOwned>(params: Value) -> Result<T, ErrorObject> {
+    serde_json::from_value(params).map_err(|e| ErrorObject {
+        code: INVALID_PARAMS,
+        message: e.to_string(),
+    })
+}
@@ -0,0 +1,535 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+};
+
+use clap::Args;
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+use tower_lsp::{
+    jsonrpc::{Error as RpcError, Result as RpcResult},
+    lsp_types::*,
+    Client, LanguageServer, LspService, Server,
+};
+use tracing::error;
+
+use crate::code;
+use crate::context::{self, ContextBlock};
+use crate::region::LineRange;
+use crate::*;
+
+const APPLY_COMMAND: &str = "hackathon.applyCodeAction";
+
+#[derive(Args, Debug)]
+pub struct LspArgs {
+    /// Communicate over stdin and stdout. This is the only transport, and is accepted since most
+    /// editors pass it by default.
+    #[arg(long)]
+    stdio: bool,
+}
+
+/// Serves the code-generation path of `hackathon code` as LSP code actions.
+pub async fn execute_lsp(_args: LspArgs, client: Box<dyn AiClient>) -> anyhow::Result<()> {
+    let ai: Arc<dyn AiClient> = Arc::from(client);
+    let (service, socket) = LspService::new(|client| Backend {
+        client,
+        ai,
+        documents: RwLock::new(HashMap::new()),
+        resolve_support: RwLock::new(false),
+        root: RwLock::new(None),
+        edit_support: RwLock::new(EditSupport::default()),
+    });
+    Server::new(tokio::io::stdin(), tokio::io::stdout(), socket)
+        .serve(service)
+        .await;
+    Ok(())
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum AiAction {
+    GenerateTests,
+    ExplainSelection,
+    RefactorSelection,
+}
+
+impl AiAction {
+    fn title(self) -> &'static str {
+        match self {
+            AiAction::GenerateTests => "AI: Generate tests",
+            AiAction::ExplainSelection => "AI: Explain selection",
+            AiAction::RefactorSelection => "AI: Refactor selection",
+        }
+    }
+
+    fn prompt(self) -> &'static str {
+        match self {
+            AiAction::GenerateTests => "Generate tests",
+            AiAction::ExplainSelection => {
+                "Explain this code by adding comments to it. Do not change what the code does."
+            }
+            AiAction::RefactorSelection => {
+                "Refactor this code to be clearer and more idiomatic. Do not change what the code does."
+            }
+        }
+    }
+
+    /// Whether the action works on the selection, rather than the whole document.
+    fn uses_selection(self) -> bool {
+        !matches!(self, AiAction::GenerateTests)
+    }
+}
+
+/// Identifies an action between `textDocument/codeAction` and its resolution.
+#[derive(Serialize, Deserialize, Debug)]
+struct ActionData {
+    action: AiAction,
+    uri: Url,
+    range: Range,
+}
+
+struct Backend {
+    client: Client,
+    ai: Arc<dyn AiClient>,
+    documents: RwLock<HashMap<Url, String>>,
+    /// Whether the editor supports `codeAction/resolve`. When it does not, actions are run
+    /// through `workspace/executeCommand` instead, so that no model calls happen until an action
+    /// is picked.
+    resolve_support: RwLock<bool>,
+    /// The first workspace folder, which relative paths in the model's replies are taken from.
+    root: RwLock<Option<PathBuf>>,
+    edit_support: RwLock<EditSupport>,
+}
+
+/// What the editor supports in a `WorkspaceEdit` beyond plain `changes`.
+#[derive(Clone, Copy, Debug, Default)]
+struct EditSupport {
+    document_changes: bool,
+    create_files: bool,
+}
+
+impl Backend {
+    /// Runs the action against the model, returning the edit to apply. Actions on the selection
+    /// replace it. Whole-document actions rewrite the document when the model annotated a block
+    /// with its path, and otherwise add each block to the end of the file in the workspace it was
+    /// annotated with, or of the document for a block without one. Files that do not exist are
+    /// created if the editor supports it.
+    async fn edit(&self, data: &ActionData) -> RpcResult<WorkspaceEdit> {
+        let documents = self.documents.read().await;
+        let text = documents
+            .get(&data.uri)
+            .ok_or_else(|| RpcError::invalid_params(format!("unknown document: {}", data.uri)))?;
+        let range = if data.action.uses_selection() {
+            data.range
+        } else {
+            Range::new(Position::new(0, 0), end_position(text))
+        };
+        let start = offset(text, range.start);
+        let end = offset(text, range.end);
+        let selected = text[start..end].to_string();
+        let context = context::render(&[ContextBlock::File {
+            path: data
+                .uri
+                .to_file_path()
+                .map_or_else(|()| data.uri.to_string(), |p| p.display().to_string()),
+            content: selected.clone(),
+            lines: data.action.uses_selection().then_some(LineRange {
+                start_line: range.start.line as usize + 1,
+                end_line: range.end.line as usize + 1,
+            }),
+        }]);
+        drop(documents);
+
+        let (code_objects, _) = code::generate(
+            self.ai.as_ref(),
+            data.action.prompt(),
+            &context,
+            Vec::new(),
+            &mut |_| {},
+        )
+        .await
+        .map_err(|e| RpcError {
+            code: tower_lsp::jsonrpc::ErrorCode::InternalError,
+            message: e.to_string().into(),
+            data: None,
+        })?;
+        if code_objects.is_empty() {
+            return Err(RpcError {
+                code: tower_lsp::jsonrpc::ErrorCode::InternalError,
+                message: "the model did not respond with any code".into(),
+                data: None,
+            });
+        }
+
+        let root = self.root.read().await.clone();
+        let document_path = data.uri.to_file_path().ok();
+        let document = document_path.as_deref().and_then(|p| p.canonicalize().ok());
+        let root = root.or_else(|| document_path.as_deref()?.parent().map(Path::to_path_buf));
+        let support = *self.edit_support.read().await;
+        let documents = self.documents.read().await;
+        let mut edited = HashSet::new();
+        // The edits in order, each with whether its file has to be created first.
+        let mut edits = Vec::new();
+        for code_object in code_objects {
+            let uri = match (&code_object.file_path, data.action.uses_selection()) {
+                (_, true) | (None, false) => data.uri.clone(),
+                (Some(path), false) => match root.as_deref().and_then(|root| resolve(path, root)) {
+                    Some(path) if Some(&path) == document.as_ref() => data.uri.clone(),
+                    Some(path) => match Url::from_file_path(path) {
+                        Ok(uri) => uri,
+                        Err(()) => continue,
+                    },
+                    None => {
+                        error!("Ignoring code for {}, outside of the workspace", path);
+                        continue;
+                    }
+                },
+            };
+            // Only the first block for each file is used.
+            if !edited.insert(uri.clone()) {
+                continue;
+            }
+            let mut new_text = code_object.code;
+            // Only the document the model was given is rewritten, by a block annotated with its
+            // path or in place of the selection. Other blocks are new code, added to the end of
+            // their file.
+            let replace = data.action.uses_selection() || code_object.file_path.is_some();
+            let (range, create) = if uri == data.uri && replace {
+                // The parser terminates every line, so only keep a trailing newline the original
+                // had.
+                if !selected.ends_with('\n') && new_text.ends_with('\n') {
+                    new_text.pop();
+                }
+                (range, false)
+            } else {
+                let text = match documents.get(&uri) {
+                    Some(text) => Some(text.clone()),
+                    None => read_file(&uri).await,
+                };
+                match text {
+                    Some(text) => {
+                        new_text.insert_str(0, separator(&text));
+                        let end = end_position(&text);
+                        (Range::new(end, end), false)
+                    }
+                    None if support.create_files => (Range::default(), true),
+                    None => {
+                        error!(
+                            "Ignoring code for {}, as the editor cannot create files",
+                            uri
+                        );
+                        continue;
+                    }
+                }
+            };
+            edits.push((uri, create, TextEdit { range, new_text }));
+        }
+        if edits.is_empty() {
+            return Err(RpcError {
+                code: tower_lsp::jsonrpc::ErrorCode::InternalError,
+                message: "the model did not respond with any code for this workspace".into(),
+                data: None,
+            });
+        }
+
+        if !support.document_changes {
+            let mut changes = HashMap::<_, Vec<_>>::new();
+            for (uri, _, edit) in edits {
+                changes.entry(uri).or_default().push(edit);
+            }
+            return Ok(WorkspaceEdit {
+                changes: Some(changes),
+                ..Default::default()
+            });
+        }
+        let mut operations = Vec::new();
+        for (uri, create, edit) in edits {
+            if create {
+                operations.push(DocumentChangeOperation::Op(ResourceOp::Create(
+                    CreateFile {
+                        uri: uri.clone(),
+                        options: None,
+                        annotation_id: None,
+                    },
+                )));
+            }
+            operations.push(DocumentChangeOperation::Edit(TextDocumentEdit {
+                text_document: OptionalVersionedTextDocumentIdentifier { uri, version: None },
+                edits: vec![OneOf::Left(edit)],
+            }));
+        }
+        Ok(WorkspaceEdit {
+            document_changes: Some(DocumentChanges::Operations(operations)),
+            ..Default::default()
+        })
+    }
+}
+
+#[tower_lsp::async_trait]
+impl LanguageServer for Backend {
+    async fn initialize(&self, params: InitializeParams) -> RpcResult<InitializeResult> {
+        let workspace_edit = params
+            .capabilities
+            .workspace
+            .as_ref()
+            .and_then(|w| w.workspace_edit.as_ref());
+        let document_changes = workspace_edit.and_then(|w| w.document_changes) == Some(true);
+        *self.edit_support.write().await = EditSupport {
+            document_changes,
+            create_files: document_changes
+                && workspace_edit
+                    .and_then(|w| w.resource_operations.as_ref())
+                    .is_some_and(|ops| ops.contains(&ResourceOperationKind::Create)),
+        };
+        let resolve_support = params
+            .capabilities
+            .text_document
+            .and_then(|t| t.code_action)
+            .and_then(|c| c.resolve_support)
+            .is_some_and(|r| r.properties.iter().any(|p| p == "edit"));
+        *self.resolve_support.write().await = resolve_support;
+        *self.root.write().await = params
+            .workspace_folders
+            .as_ref()
+            .and_then(|folders| folders.first())
+            .and_then(|folder| folder.uri.to_file_path().ok());
+
+        Ok(InitializeResult {
+            capabilities: ServerCapabilities {
+                text_document_sync: Some(TextDocumentSyncCapability::Kind(
+                    TextDocumentSyncKind::FULL,
+                )),
+                code_action_provider: Some(CodeActionProviderCapability::Options(
+                    CodeActionOptions {
+                        code_action_kinds: Some(vec![CodeActionKind::REFACTOR_REWRITE]),
+                        resolve_provider: Some(resolve_support),
+                        ..Default::default()
+                    },
+                )),
+                execute_command_provider: Some(ExecuteCommandOptions {
+                    commands: vec![APPLY_COMMAND.to_string()],
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+            server_info: Some(ServerInfo {
+                name: env!("CARGO_PKG_NAME").to_string(),
+                version: Some(env!("CARGO_PKG_VERSION").to_string()),
+            }),
+        })
+    }
+
+    async fn shutdown(&self) -> RpcResult<()> {
+        Ok(())
+    }
+
+    async fn did_open(&self, params: DidOpenTextDocumentParams) {
+        self.documents
+            .write()
+            .await
+            .insert(params.text_document.uri, params.text_document.text);
+    }
+
+    async fn did_change(&self, mut params: DidChangeTextDocumentParams) {
+        // With full sync, the last change holds the whole document.
+        if let Some(change) = params.content_changes.pop() {
+            self.documents
+                .write()
+                .await
+                .insert(params.text_document.uri, change.text);
+        }
+    }
+
+    async fn did_close(&self, params: DidCloseTextDocumentParams) {
+        self.documents
+            .write()
+            .await
+            .remove(&params.text_document.uri);
+    }
+
+    async fn code_action(&self, params: CodeActionParams) -> RpcResult<Option<CodeActionResponse>> {
+        let resolve_support = *self.resolve_support.read().await;
+        let has_selection = params.range.start != params.range.end;
+        let actions = [
+            AiAction::GenerateTests,
+            AiAction::ExplainSelection,
+            AiAction::RefactorSelection,
+        ]
+        .into_iter()
+        .filter(|action| has_selection || !action.uses_selection())
+        .map(|action| {
+            let data = serde_json::to_value(ActionData {
+                action,
+                uri: params.text_document.uri.clone(),
+                range: params.range,
+            })
+            .ok();
+            let mut code_action = CodeAction {
+                title: action.title().to_string(),
+                kind: Some(CodeActionKind::REFACTOR_REWRITE),
+                ..Default::default()
+            };
+            if resolve_support {
+                code_action.data = data;
+            } else {
+                code_action.command = Some(Command {
+                    title: action.title().to_string(),
+                    command: APPLY_COMMAND.to_string(),
+                    arguments: data.map(|d| vec![d]),
+                });
+            }
+            CodeActionOrCommand::CodeAction(code_action)
+        })
+        .collect();
+        Ok(Some(actions))
+    }
+
+    async fn code_action_resolve(&self, mut code_action: CodeAction) -> RpcResult<CodeAction> {
+        let data = code_action
+            .data
+            .take()
+            .ok_or_else(|| RpcError::invalid_params("missing code action data"))?;
+        let data = serde_json::from_value::<ActionData>(data)
+            .map_err(|e| RpcError::invalid_params(e.to_string()))?;
+        code_action.edit = Some(self.edit(&data).await?);
+        Ok(code_action)
+    }
+
+    async fn execute_command(
+        &self,
+        params: ExecuteCommandParams,
+    ) -> RpcResult<Option<serde_json::Value>> {
+        if params.command != APPLY_COMMAND {
+            return Err(RpcError::method_not_found());
+        }
+        let data = params
+            .arguments
+            .into_iter()
+            .next()
+            .ok_or_else(|| RpcError::invalid_params("missing code action data"))?;
+        let data = serde_json::from_value::<ActionData>(data)
+            .map_err(|e| RpcError::invalid_params(e.to_string()))?;
+        let edit = self.edit(&data).await?;
+        if let Err(e) = self.client.apply_edit(edit).await {
+            error!("Unable to apply edit: {}", e);
+        }
+        Ok(None)
+    }
+}
+
+/// The file `path`, as annotated on a block of the model's reply, refers to under `root`, or
+/// `None` if it lies outside of it. The model's reply may be steered by anything in the document,
+/// so the path is checked the same way as by `ToolBox`, though the file itself need not exist.
+fn resolve(path: &str, root: &Path) -> Option<PathBuf> {
+    let root = root.canonicalize().ok()?;
+    let path = root.join(path);
+    // The part of the path that exists is canonicalized, and the rest may only name new
+    // directories and the file within them.
+    let mut existing = path.as_path();
+    let mut new = Vec::new();
+    let resolved = loop {
+        if let Ok(resolved) = existing.canonicalize() {
+            break resolved;
+        }
+        match existing.components().next_back()? {
+            Component::Normal(name) => new.push(name),
+            _ => return None,
+        }
+        existing = existing.parent()?;
+    };
+    let resolved = new
+        .into_iter()
+        .rev()
+        .fold(resolved, |path, name| path.join(name));
+    (resolved.starts_with(&root) && resolved != root).then_some(resolved)
+}
+
+/// What goes between the end of `text` and code added after it.
+fn separator(text: &str) -> &'static str {
+    if text.is_empty() {
+        ""
+    } else if text.ends_with('\n') {
+        "\n"
+    } else {
+        "\n\n"
+    }
+}
+
+/// Reads the file at `uri` from disk, if it exists.
+async fn read_file(uri: &Url) -> Option<String> {
+    tokio::fs::read_to_string(uri.to_file_path().ok()?)
+        .await
+        .ok()
+}
+
+/// Converts an LSP position, whose character is in UTF-16 code units, to a byte offset in `text`.
+fn offset(text: &str, position: Position) -> usize {
+    let mut line_start = 0;
+    for _ in 0..position.line {
+        match text[line_start..].find('\n') {
+            Some(i) => line_start += i + 1,
+            None => return text.len(),
+        }
+    }
+    let line = &text[line_start..];
+    let line = &line[..line.find('\n').unwrap_or(line.len())];
+    let mut units = 0;
+    for (i, c) in line.char_indices() {
+        if units >= position.character as usize {
+            return line_start + i;
+        }
+        units += c.len_utf16();
+    }
+    line_start + line.len()
+}
+
+/// The position just past the last character of `text`.
+fn end_position(text: &str) -> Position {
+    let line = text.matches('\n').count() as u32;
+    let last_line = text.rsplit('\n').next().unwrap_or("");
+    Position::new(line, last_line.encode_utf16().count() as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_offset() {
+        let text = "fn main() {\n    let s = \"héllo\";\n}\n";
+        assert_eq!(offset(text, Position::new(0, 0)), 0);
+        assert_eq!(offset(text, Position::new(1, 4)), 16);
+        // `é` is one UTF-16 unit but two bytes.
+        assert_eq!(&text[offset(text, Position::new(1, 15))..][..2], "ll");
+        assert_eq!(offset(text, Position::new(10, 0)), text.len());
+        assert_eq!(offset(text, end_position(text)), text.len());
+    }
+
+    #[test]
+    fn test_resolve() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        std::fs::create_dir(root.join("src")).unwrap();
+        std::fs::write(root.join("src/lib.rs"), "").unwrap();
+        assert_eq!(resolve("src/lib.rs", &root), Some(root.join("src/lib.rs")));
+        // Files that do not exist yet may be created, along with their directories.
+        assert_eq!(
+            resolve("src/lib_test.rs", &root),
+            Some(root.join("src/lib_test.rs"))
+        );
+        assert_eq!(
+            resolve("tests/lib.rs", &root),
+            Some(root.join("tests/lib.rs"))
+        );
+        assert_eq!(resolve("tests/../../outside.rs", &root), None);
+        assert_eq!(
+            resolve(&root.join("src/lib.rs").display().to_string(), &root),
+            Some(root.join("src/lib.rs"))
+        );
+        assert_eq!(resolve("../outside.rs", &root), None);
+        assert_eq!(resolve("src/../../outside.rs", &root), None);
+        assert_eq!(resolve("/etc/passwd", &root), None);
+        assert_eq!(resolve("src/..", &root), None);
+    }
+}
@@ -1,26 +1,42 @@
+mod backend;
 mod chat;
 mod code;
+mod context;
+mod conversations;
+mod export;
+mod jsonrpc;
+mod lsp;
+mod mcp;
+mod message;
+mod output;
+mod region;
+mod server;
+mod store;
 mod system_prompts;
+mod tokens;
+mod tools;
+use backend::BackendArgs;
 use chat::{execute_chat, ChatArgs};
 use code::{execute_code, CodeArgs};
-use system_prompts::SYSTEM_PROMPT;
+use conversations::{execute_conversations, ConversationsArgs};
+use lsp::{execute_lsp, LspArgs};
+use mcp::{execute_mcp, McpArgs};
+use message::ContentBlock;
+use server::{execute_serve, ServeArgs};
+use store::StoreArgs;
 
-use aws_config::BehaviorVersion;
 use aws_sdk_bedrockruntime::{
-    error::SdkError, operation::converse::ConverseError, types::SystemContentBlock, Client,
+    error::SdkError,
+    operation::{converse::ConverseError, converse_stream::ConverseStreamError},
+    types::error::ConverseStreamOutputError,
 };
 use serde::{Deserialize, Serialize};
-use std::{
-    fs::File,
-    io::{IsTerminal, Write},
-    path::Path,
-    sync::Arc,
-};
+use std::{fs::File, sync::Arc};
 use thiserror::Error;
-use tokio::{io::AsyncReadExt, sync::Mutex, time::Instant};
+use tools::ToolBox;
 
 use clap::{Parser, Subcommand};
-use tracing::{debug, error, info};
+use tracing::{debug, info};
 
 #[derive(Parser, Debug)]
 #[command(
@@ -29,6 +45,10 @@ use tracing::{debug, error, info};
     about = "An AI-powdered CLI for your terminal and editor."
 )]
 struct Cli {
+    #[command(flatten)]
+    backend: BackendArgs,
+    #[command(flatten)]
+    store: StoreArgs,
     #[command(subcommand)]
     command: Commands,
 }
@@ -37,105 +57,47 @@ struct Cli {
 enum Commands {
     Chat(ChatArgs),
     Code(CodeArgs),
-}
-
-#[derive(Serialize, Deserialize, Default, Debug)]
-enum ModelResponseType {
-    #[default]
-    #[serde(rename = "chat")]
-    Chat,
-    #[serde(rename = "code")]
-    Code,
-}
-
-impl std::fmt::Display for ModelResponseType {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            ModelResponseType::Chat => write!(f, "chat"),
-            ModelResponseType::Code => write!(f, "code"),
-        }
-    }
-}
-
-#[derive(Serialize, Deserialize, Default, Debug)]
-struct ModelResponse {
-    #[serde(rename = "type")]
-    type_: ModelResponseType,
-    // #[serde(deserialize_with = "deserialize_with_escaped_newline")]
-    message: Vec<String>,
+    /// List the models available to the selected backend.
+    Models,
+    /// Run a long-lived JSON-RPC server for editor clients.
+    Serve(ServeArgs),
+    /// Run a language server offering AI code actions.
+    Lsp(LspArgs),
+    /// Run a Model Context Protocol server exposing chat and code as tools.
+    Mcp(McpArgs),
+    /// List, show, rename, export or delete stored conversations.
+    Conversations(ConversationsArgs),
 }
 
 // -----------------------------------------------------------------------------------------------
-#[derive(Serialize, Deserialize, Clone, Debug)]
-struct StorableMessage {
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct StorableMessage {
     role: String,
-    content: String,
-}
-
-// This represents the Anthropic/Claude message format for Bedrock
-#[derive(Serialize, Deserialize)]
-struct AnthropicMessage {
-    role: String,
-    content: Vec<AnthropicContent>,
-}
-
-#[derive(Serialize, Deserialize)]
-struct AnthropicContent {
-    #[serde(rename = "type")]
-    content_type: String,
-    text: String,
+    #[serde(deserialize_with = "message::deserialize_content")]
+    content: Vec<ContentBlock>,
+    /// When the message was sent or received. Unknown for messages stored before version 3 and
+    /// for summaries.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    created_at: Option<chrono::DateTime<chrono::Utc>>,
 }
 
-impl From<&StorableMessage> for AnthropicMessage {
-    fn from(msg: &StorableMessage) -> Self {
-        AnthropicMessage {
-            role: msg.role.clone(),
-            content: vec![AnthropicContent {
-                content_type: "text".to_string(),
-                text: msg.content.clone(),
-            }],
+impl StorableMessage {
+    /// A message with a single text block.
+    pub fn new(role: impl Into<String>, text: impl Into<String>) -> Self {
+        Self {
+            role: role.into(),
+            content: vec![ContentBlock::text(text)],
+            created_at: None,
         }
     }
-}
-
-impl From<AnthropicMessage> for StorableMessage {
-    fn from(msg: AnthropicMessage) -> Self {
-        let content = msg
-            .content
-            .into_iter()
-            .map(|c| c.text)
-            .collect::<Vec<String>>()
-            .join("\n");
-
-        StorableMessage {
-            role: msg.role,
-            content,
-        }
-    }
-}
-
-#[allow(clippy::from_over_into)]
-impl Into<aws_sdk_bedrockruntime::types::ContentBlock> for &AnthropicContent {
-    fn into(self) -> aws_sdk_bedrockruntime::types::ContentBlock {
-        aws_sdk_bedrockruntime::types::ContentBlock::Text(self.text.clone())
-    }
-}
 
-impl TryInto<aws_sdk_bedrockruntime::types::Message> for &AnthropicMessage {
-    type Error = anyhow::Error;
-    fn try_into(self) -> Result<aws_sdk_bedrockruntime::types::Message, Self::Error> {
-        Ok(aws_sdk_bedrockruntime::types::Message::builder()
-            .role(match self.role.as_str() {
-                "user" => aws_sdk_bedrockruntime::types::ConversationRole::User,
-                _ => aws_sdk_bedrockruntime::types::ConversationRole::Assistant,
-            })
-            .set_content(Some(
-                self.content
-                    .iter()
-                    .map(|c| c.into())
-                    .collect::<Vec<aws_sdk_bedrockruntime::types::ContentBlock>>(),
-            ))
-            .build()?)
+    /// The blocks of the message as plain text, one after the other.
+    pub fn text(&self) -> String {
+        self.content
+            .iter()
+            .map(ContentBlock::to_text)
+            .collect::<Vec<_>>()
+            .join("\n")
     }
 }
 
@@ -148,9 +110,22 @@ async fn main() -> anyhow::Result<()> {
 
     debug!("Executing command: {:?}", cli);
 
+    let backend = &cli.backend;
     match cli.command {
-        Commands::Chat(args) => execute_chat(args).await?,
-        Commands::Code(args) => execute_code(args).await?,
+        Commands::Chat(args) => {
+            execute_chat(args, &cli.store, backend.build().await?.as_ref()).await?
+        }
+        Commands::Code(args) => execute_code(args, backend.build().await?.as_ref()).await?,
+        Commands::Models => {
+            for model in backend.build().await?.list_models().await? {
+                println!("{}", model);
+            }
+        }
+        Commands::Serve(args) => execute_serve(args, &cli.store, backend.build().await?).await?,
+        Commands::Lsp(args) => execute_lsp(args, backend.build().await?).await?,
+        Commands::Mcp(args) => execute_mcp(args, &cli.store, backend.build().await?).await?,
+        // Managing stored conversations never talks to a model, so it needs no credentials.
+        Commands::Conversations(args) => execute_conversations(args, &cli.store).await?,
     }
 
     Ok(())
@@ -162,15 +137,72 @@ pub struct Message {
     pub prompt: String,
     /// A code block to be used as context in the conversation, without referencing a specific file path.
     pub free_context: String,
+    /// Images and documents attached to the prompt.
+    pub attachments: Vec<ContentBlock>,
 }
 
-const MODEL_ID: &str = "anthropic.claude-3-haiku-20240307-v1:0";
-const CLAUDE_REGION: &str = "us-west-2";
+impl From<Message> for StorableMessage {
+    fn from(message: Message) -> Self {
+        let mut content = Vec::new();
+        if !message.free_context.is_empty() {
+            content.push(ContentBlock::text(message.free_context));
+        }
+        content.extend(message.attachments);
+        content.push(ContentBlock::text(message.prompt));
+        StorableMessage {
+            role: "user".to_string(),
+            content,
+            created_at: Some(chrono::Utc::now()),
+        }
+    }
+}
+
+/// Everything a backend needs to produce the next assistant message.
+#[derive(Debug, Clone)]
+pub struct ModelRequest {
+    pub system_prompt: String,
+    /// The conversation so far, ending with the user message to respond to.
+    pub messages: Vec<StorableMessage>,
+    /// Local tools the model may call before answering. Backends without tool support ignore them.
+    pub tools: Option<Arc<ToolBox>>,
+}
 
+/// A model provider. `execute_chat` and `execute_code` only ever talk to the model through this
+/// trait, so backends can be swapped with `--backend`.
 #[async_trait::async_trait]
-pub trait AiClient {
-    async fn send_message(&self, message: Message)
-        -> Result<SendMessageResponse, SendMessageError>;
+pub trait AiClient: Send + Sync {
+    /// The identifier of the model requests are sent to.
+    fn model_id(&self) -> &str;
+
+    /// The most tokens a request may use, leaving room for the reply.
+    fn max_input_tokens(&self) -> usize {
+        tokens::max_input_tokens(self.model_id())
+    }
+
+    async fn send_message(
+        &self,
+        request: ModelRequest,
+    ) -> Result<SendMessageResponse, SendMessageError>;
+
+    /// Like `send_message`, but calls `on_delta` with each chunk of the reply as it arrives.
+    ///
+    /// Backends that cannot stream deliver the whole reply as a single chunk.
+    async fn send_message_stream(
+        &self,
+        request: ModelRequest,
+        on_delta: &mut (dyn for<'d> FnMut(&'d str) + Send),
+    ) -> Result<SendMessageResponse, SendMessageError> {
+        let response = self.send_message(request).await?;
+        on_delta(&response.message);
+        Ok(response)
+    }
+
+    /// Lists the models available to this backend.
+    async fn list_models(&self) -> Result<Vec<String>, SendMessageError> {
+        Err(SendMessageError::Custom(
+            "Listing models is not supported by this backend".into(),
+        ))
+    }
 }
 
 #[derive(Error, Debug)]
@@ -185,20 +217,46 @@ pub enum SendMessageError {
     ConverseError(#[from] ConverseError),
 
     #[error("{}", .0)]
-    SdkError(#[from] SdkError<ConverseError>),
+    SdkError(#[from] Box<SdkError<ConverseError>>),
+
+    #[error("{}", .0)]
+    ConverseStreamError(#[from] ConverseStreamError),
+
+    #[error("{}", .0)]
+    ConverseStreamOutputError(#[from] ConverseStreamOutputError),
+
+    #[error("{}", .0)]
+    Http(#[from] reqwest::Error),
+
+    #[error("{}", .0)]
+    BuildError(#[from] aws_smithy_types::error::operation::BuildError),
 }
 
 #[derive(Debug)]
-pub enum SendMessageResponse {
-    Chat(String),
-    Code { language: String, code: String },
+pub struct SendMessageResponse {
+    /// The text of the assistant's reply.
+    pub message: String,
+    /// Token counts for the request, for backends that report them.
+    pub usage: Option<TokenUsage>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TokenUsage {
+    pub input_tokens: u32,
+    pub output_tokens: u32,
+}
+
+impl std::ops::AddAssign for TokenUsage {
+    fn add_assign(&mut self, other: Self) {
+        self.input_tokens += other.input_tokens;
+        self.output_tokens += other.output_tokens;
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use aws_config::BehaviorVersion;
     use aws_sdk_bedrockruntime::{
-        operation::converse_stream::ConverseStreamOutput,
         types::{
             ContentBlock, ConversationRole, ConverseStreamOutput as ConverseStreamOutputType,
             Message, SystemContentBlock,
@@ -207,12 +265,13 @@ mod tests {
     };
     use tracing::{debug, error};
 
-    use super::*;
+    use crate::system_prompts::SYSTEM_PROMPT;
 
     const MODEL_ID: &str = "anthropic.claude-3-haiku-20240307-v1:0";
     const CLAUDE_REGION: &str = "us-west-2";
 
     #[tokio::test]
+    #[ignore = "calls the live Bedrock API and requires AWS credentials"]
     async fn test_api() {
         let _ = tracing_subscriber::fmt::try_init();
 
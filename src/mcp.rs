@@ -0,0 +1,233 @@
+use std::{path::Path, sync::Arc};
+
+use clap::Args;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+use crate::chat;
+use crate::code;
+use crate::context;
+use crate::jsonrpc::{self, ErrorObject, Request, Response};
+use crate::store::{Conversation, ConversationStore, StoreArgs};
+use crate::*;
+
+/// The protocol versions this server understands, newest first.
+const PROTOCOL_VERSIONS: &[&str] = &["2025-06-18", "2025-03-26", "2024-11-05"];
+
+const DEFAULT_CONVERSATION_ID: &str = "mcp";
+
+#[derive(Args, Debug)]
+pub struct McpArgs {
+    /// The repository whose `.db` conversations the `chat` tool uses as memory.
+    #[arg(short, long, default_value = ".")]
+    current_repo_dir: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct InitializeParams {
+    #[serde(rename = "protocolVersion")]
+    protocol_version: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct CallToolParams {
+    name: String,
+    #[serde(default)]
+    arguments: Value,
+}
+
+#[derive(Deserialize, Debug)]
+struct ChatArguments {
+    prompt: String,
+    conversation_id: Option<String>,
+    #[serde(default)]
+    context: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct CodeArguments {
+    prompt: String,
+    #[serde(default)]
+    context: String,
+}
+
+/// Serves `chat` and `code` as Model Context Protocol tools over stdio, so that other agents can
+/// use this binary as a sub-agent.
+pub async fn execute_mcp(
+    args: McpArgs,
+    store: &StoreArgs,
+    client: Box<dyn AiClient>,
+) -> anyhow::Result<()> {
+    let server = McpServer {
+        client,
+        store: store.open(Path::new(&args.current_repo_dir)).await?,
+    };
+
+    let mut stdout = tokio::io::stdout();
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = match serde_json::from_str::<Request>(&line) {
+            Ok(request) => server.handle(request).await,
+            Err(e) => Some(Response::error(
+                Value::Null,
+                jsonrpc::PARSE_ERROR,
+                e.to_string(),
+            )),
+        };
+        if let Some(response) = response {
+            stdout
+                .write_all(serde_json::to_string(&response)?.as_bytes())
+                .await?;
+            stdout.write_all(b"\n").await?;
+            stdout.flush().await?;
+        }
+    }
+
+    Ok(())
+}
+
+struct McpServer {
+    client: Box<dyn AiClient>,
+    store: Arc<dyn ConversationStore>,
+}
+
+impl McpServer {
+    async fn handle(&self, request: Request) -> Option<Response> {
+        debug!("Received request: {:?}", request);
+        // Notifications, e.g. `notifications/initialized`, need no response.
+        let id = request.id?;
+
+        let result = match request.method.as_str() {
+            "initialize" => self.initialize(request.params),
+            "ping" => Ok(json!({})),
+            "tools/list" => Ok(tools()),
+            "tools/call" => self.call_tool(request.params).await,
+            method => Err(ErrorObject {
+                code: jsonrpc::METHOD_NOT_FOUND,
+                message: format!("unknown method: {}", method),
+            }),
+        };
+        Some(match result {
+            Ok(result) => Response::result(id, result),
+            Err(e) => Response::error(id, e.code, e.message),
+        })
+    }
+
+    fn initialize(&self, params: Value) -> Result<Value, ErrorObject> {
+        let params = jsonrpc::params::<InitializeParams>(params)?;
+        let protocol_version = PROTOCOL_VERSIONS
+            .iter()
+            .find(|v| **v == params.protocol_version)
+            .unwrap_or(&PROTOCOL_VERSIONS[0]);
+        Ok(json!({
+            "protocolVersion": protocol_version,
+            "capabilities": { "tools": { "listChanged": false } },
+            "serverInfo": {
+                "name": env!("CARGO_PKG_NAME"),
+                "version": env!("CARGO_PKG_VERSION"),
+            },
+        }))
+    }
+
+    /// Failures of the tool itself are reported in the result with `isError`, so that the calling
+    /// model can see them, rather than as protocol errors.
+    async fn call_tool(&self, params: Value) -> Result<Value, ErrorObject> {
+        let params = jsonrpc::params::<CallToolParams>(params)?;
+        let result = match params.name.as_str() {
+            "chat" => self.chat(jsonrpc::params(params.arguments)?).await,
+            "code" => self.code(jsonrpc::params(params.arguments)?).await,
+            name => {
+                return Err(ErrorObject {
+                    code: jsonrpc::INVALID_PARAMS,
+                    message: format!("unknown tool: {}", name),
+                })
+            }
+        };
+        let (text, is_error) = match result {
+            Ok(text) => (text, false),
+            Err(e) => (e.to_string(), true),
+        };
+        Ok(json!({
+            "content": [{ "type": "text", "text": text }],
+            "isError": is_error,
+        }))
+    }
+
+    async fn chat(&self, arguments: ChatArguments) -> anyhow::Result<String> {
+        let conversation_id = arguments
+            .conversation_id
+            .unwrap_or_else(|| DEFAULT_CONVERSATION_ID.to_string());
+        let mut conversation = Conversation::open(self.store.clone(), &conversation_id).await?;
+        let response = chat::send(
+            self.client.as_ref(),
+            &mut conversation,
+            Message {
+                prompt: arguments.prompt,
+                free_context: context::render_active_buffer(arguments.context),
+                attachments: Vec::new(),
+            },
+            None,
+            &mut |_| {},
+        )
+        .await?;
+        Ok(response.message)
+    }
+
+    async fn code(&self, arguments: CodeArguments) -> anyhow::Result<String> {
+        let (code_objects, _) = code::generate(
+            self.client.as_ref(),
+            &arguments.prompt,
+            &context::render_active_buffer(arguments.context),
+            Vec::new(),
+            &mut |_| {},
+        )
+        .await?;
+        Ok(code_objects
+            .iter()
+            .map(|c| format!("```{}\n{}```", c.language, c.code))
+            .collect::<Vec<_>>()
+            .join("\n\n"))
+    }
+}
+
+fn tools() -> Value {
+    json!({
+        "tools": [
+            {
+                "name": "chat",
+                "description": "Ask an expert programmer a question. Conversations are remembered per conversation_id in the repository, so follow-up questions can refer to earlier answers.",
+                "inputSchema": {
+                    "type": "object",
+                    "properties": {
+                        "prompt": { "type": "string", "description": "The question to ask." },
+                        "conversation_id": {
+                            "type": "string",
+                            "description": "The conversation to continue. Defaults to a shared conversation."
+                        },
+                        "context": {
+                            "type": "string",
+                            "description": "Code or other text the question is about."
+                        }
+                    },
+                    "required": ["prompt"]
+                }
+            },
+            {
+                "name": "code",
+                "description": "Generate or modify code. Returns the updated code as markdown code blocks.",
+                "inputSchema": {
+                    "type": "object",
+                    "properties": {
+                        "prompt": { "type": "string", "description": "The change to make, e.g. 'generate tests'." },
+                        "context": { "type": "string", "description": "The code to modify." }
+                    },
+                    "required": ["prompt"]
+                }
+            }
+        ]
+    })
+}
@@ -0,0 +1,235 @@
+//! The content of a stored message, as distinct blocks rather than a single string.
+
+use std::path::Path;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::Value;
+
+/// The version of the stored message format, written alongside conversations. Version 1 stored
+/// the content of each message as a single string, which is still read as one text block.
+/// Version 3 added the time each message was sent.
+pub const SCHEMA_VERSION: u32 = 3;
+
+/// A piece of a message.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ContentBlock {
+    Text {
+        text: String,
+    },
+    /// An image attached to the message.
+    Image {
+        /// The file it was attached from.
+        name: String,
+        format: ImageFormat,
+        #[serde(with = "base64_bytes")]
+        data: Vec<u8>,
+    },
+    /// A document, e.g. a PDF, attached to the message.
+    Document {
+        /// The file it was attached from.
+        name: String,
+        format: DocumentFormat,
+        #[serde(with = "base64_bytes")]
+        data: Vec<u8>,
+    },
+    /// A tool the model asked to run.
+    ToolUse {
+        id: String,
+        name: String,
+        input: Value,
+    },
+    /// The output of a tool the model ran, answering the `ToolUse` with `tool_use_id`.
+    ToolResult {
+        tool_use_id: String,
+        text: String,
+        #[serde(default)]
+        is_error: bool,
+    },
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageFormat {
+    Png,
+    Jpeg,
+    Gif,
+    Webp,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DocumentFormat {
+    Pdf,
+    Csv,
+    Doc,
+    Docx,
+    Xls,
+    Xlsx,
+}
+
+impl ImageFormat {
+    /// Detects the format of an image from the magic bytes it starts with.
+    pub fn detect(data: &[u8]) -> Option<Self> {
+        if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+            Some(ImageFormat::Png)
+        } else if data.starts_with(&[0xff, 0xd8, 0xff]) {
+            Some(ImageFormat::Jpeg)
+        } else if data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a") {
+            Some(ImageFormat::Gif)
+        } else if data.starts_with(b"RIFF") && data.get(8..12) == Some(b"WEBP") {
+            Some(ImageFormat::Webp)
+        } else {
+            None
+        }
+    }
+}
+
+impl DocumentFormat {
+    /// Detects the format of a document that is attached rather than sent as text, from the magic
+    /// bytes it starts with and the extension of `path`. Plain text formats are left to be sent
+    /// as text, except for CSV.
+    pub fn detect(path: &Path, data: &[u8]) -> Option<Self> {
+        const ZIP: &[u8] = b"PK\x03\x04";
+        const OLE: &[u8] = &[0xd0, 0xcf, 0x11, 0xe0, 0xa1, 0xb1, 0x1a, 0xe1];
+        let extension = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(str::to_lowercase);
+        match extension.as_deref() {
+            _ if data.starts_with(b"%PDF-") => Some(DocumentFormat::Pdf),
+            Some("docx") if data.starts_with(ZIP) => Some(DocumentFormat::Docx),
+            Some("xlsx") if data.starts_with(ZIP) => Some(DocumentFormat::Xlsx),
+            Some("doc") if data.starts_with(OLE) => Some(DocumentFormat::Doc),
+            Some("xls") if data.starts_with(OLE) => Some(DocumentFormat::Xls),
+            Some("csv") => Some(DocumentFormat::Csv),
+            _ => None,
+        }
+    }
+
+    /// Whether documents of this format are plain text.
+    pub fn is_text(self) -> bool {
+        matches!(self, DocumentFormat::Csv)
+    }
+}
+
+impl ContentBlock {
+    pub fn text(text: impl Into<String>) -> Self {
+        ContentBlock::Text { text: text.into() }
+    }
+
+    /// The block as plain text, for backends and commands that only deal in text. Blocks other
+    /// than text are described rather than included.
+    pub fn to_text(&self) -> String {
+        match self {
+            ContentBlock::Text { text } => text.clone(),
+            ContentBlock::Image { name, .. } => format!("[image: {}]", name),
+            ContentBlock::Document { name, .. } => format!("[document: {}]", name),
+            ContentBlock::ToolUse { name, input, .. } => format!("[tool call: {} {}]", name, input),
+            ContentBlock::ToolResult { text, .. } => text.clone(),
+        }
+    }
+}
+
+/// Reads the content of a message in either the current format, a list of blocks, or the
+/// version 1 format, a single string.
+pub(crate) fn deserialize_content<'de, D>(deserializer: D) -> Result<Vec<ContentBlock>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum StoredContent {
+        Text(String),
+        Blocks(Vec<ContentBlock>),
+    }
+
+    Ok(match StoredContent::deserialize(deserializer)? {
+        StoredContent::Text(text) => vec![ContentBlock::text(text)],
+        StoredContent::Blocks(blocks) => blocks,
+    })
+}
+
+/// Binary data as a base64 string, so that stored conversations stay plain JSON.
+mod base64_bytes {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(data: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&STANDARD.encode(data))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        STANDARD.decode(encoded).map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::StorableMessage;
+
+    #[test]
+    fn test_read_version_1() {
+        let message: StorableMessage =
+            serde_json::from_str(r#"{"role":"user","content":"hi"}"#).unwrap();
+        assert_eq!(message, StorableMessage::new("user", "hi"));
+    }
+
+    #[test]
+    fn test_detect_image_format() {
+        assert_eq!(
+            ImageFormat::detect(b"\x89PNG\r\n\x1a\n..."),
+            Some(ImageFormat::Png)
+        );
+        assert_eq!(
+            ImageFormat::detect(&[0xff, 0xd8, 0xff, 0xe0]),
+            Some(ImageFormat::Jpeg)
+        );
+        assert_eq!(ImageFormat::detect(b"GIF89a..."), Some(ImageFormat::Gif));
+        assert_eq!(
+            ImageFormat::detect(b"RIFF\0\0\0\0WEBPVP8 "),
+            Some(ImageFormat::Webp)
+        );
+        assert_eq!(ImageFormat::detect(b"fn main() {}"), None);
+    }
+
+    #[test]
+    fn test_detect_document_format() {
+        let detect = |path: &str, data: &[u8]| DocumentFormat::detect(Path::new(path), data);
+        assert_eq!(detect("spec", b"%PDF-1.7"), Some(DocumentFormat::Pdf));
+        assert_eq!(
+            detect("spec.DOCX", b"PK\x03\x04"),
+            Some(DocumentFormat::Docx)
+        );
+        assert_eq!(detect("spec.docx", b"not a zip"), None);
+        assert_eq!(detect("data.csv", b"id,name\n"), Some(DocumentFormat::Csv));
+        assert_eq!(detect("README.md", b"# Hackathon"), None);
+        assert_eq!(detect("src/main.rs", b"fn main() {}"), None);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let message = StorableMessage {
+            role: "user".into(),
+            content: vec![
+                ContentBlock::text("what is this?"),
+                ContentBlock::Image {
+                    name: "architecture.png".into(),
+                    format: ImageFormat::Png,
+                    data: vec![0x89, b'P', b'N', b'G'],
+                },
+            ],
+            created_at: None,
+        };
+        let json = serde_json::to_value(&message).unwrap();
+        assert_eq!(json["content"][1]["type"], "image");
+        assert_eq!(json["content"][1]["data"], "iVBORw==");
+        assert_eq!(
+            serde_json::from_value::<StorableMessage>(json).unwrap(),
+            message
+        );
+        assert_eq!(message.text(), "what is this?\n[image: architecture.png]");
+    }
+}
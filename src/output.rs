@@ -0,0 +1,129 @@
+use std::io::Write;
+
+use clap::ValueEnum;
+use serde::Serialize;
+
+use crate::{code::CodeObject, TokenUsage};
+
+/// Bumped whenever an event is changed in a way that existing clients could not parse.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Print the whole response once it is complete.
+    #[default]
+    Text,
+    /// Print one JSON event per line as the response streams in.
+    Ndjson,
+}
+
+impl OutputFormat {
+    /// Emits the `start` event of an ndjson stream.
+    pub fn start(self, kind: EventKind, model: &str) -> anyhow::Result<()> {
+        if self == OutputFormat::Ndjson {
+            Event::Start {
+                version: PROTOCOL_VERSION,
+                kind,
+                model,
+            }
+            .emit()?;
+        }
+        Ok(())
+    }
+
+    /// Ends an ndjson stream with `done`, or `error` if the command failed.
+    pub fn finish(self, result: anyhow::Result<()>) -> anyhow::Result<()> {
+        if self == OutputFormat::Ndjson {
+            match &result {
+                Ok(()) => Event::Done.emit()?,
+                Err(e) => Event::Error {
+                    message: e.to_string(),
+                }
+                .emit()?,
+            }
+        }
+        result
+    }
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    Chat,
+    Code,
+}
+
+/// A single line of `--output ndjson`.
+///
+/// Every stream starts with `start`, and ends with either `done` or `error`.
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event<'a> {
+    Start {
+        version: u32,
+        kind: EventKind,
+        model: &'a str,
+    },
+    Delta {
+        text: &'a str,
+    },
+    CodeBlock(&'a CodeObject),
+    Usage(TokenUsage),
+    Error {
+        message: String,
+    },
+    Done,
+}
+
+impl Event<'_> {
+    /// Writes the event as a single line to stdout, flushing so clients see it immediately.
+    pub fn emit(&self) -> anyhow::Result<()> {
+        let mut stdout = std::io::stdout().lock();
+        serde_json::to_writer(&mut stdout, self)?;
+        writeln!(stdout)?;
+        stdout.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_serialize_events() {
+        let start = Event::Start {
+            version: PROTOCOL_VERSION,
+            kind: EventKind::Code,
+            model: "llama3.2",
+        };
+        assert_eq!(
+            serde_json::to_string(&start).unwrap(),
+            r#"{"type":"start","version":1,"kind":"code","model":"llama3.2"}"#
+        );
+
+        let block = CodeObject {
+            language: "rust".into(),
+            code: "fn main() {}".into(),
+            file_path: None,
+            range: None,
+        };
+        assert_eq!(
+            serde_json::to_string(&Event::CodeBlock(&block)).unwrap(),
+            r#"{"type":"code_block","language":"rust","code":"fn main() {}","file_path":null}"#
+        );
+
+        let usage = Event::Usage(TokenUsage {
+            input_tokens: 10,
+            output_tokens: 20,
+        });
+        assert_eq!(
+            serde_json::to_string(&usage).unwrap(),
+            r#"{"type":"usage","input_tokens":10,"output_tokens":20}"#
+        );
+        assert_eq!(
+            serde_json::to_string(&Event::Done).unwrap(),
+            r#"{"type":"done"}"#
+        );
+    }
+}
@@ -0,0 +1,288 @@
+//! Finds regions of source files, such as the function around a cursor, without parsing them.
+//!
+//! This is a heuristic based on indentation and definition keywords, so that it works the same for
+//! every language, at the cost of occasionally picking a region that is too small or too large.
+
+use serde::Serialize;
+
+/// How far above the cursor to look for the start of a definition.
+const MAX_SCAN_LINES: usize = 300;
+/// How many lines around the cursor to use when it is not inside a definition.
+const FALLBACK_LINES: usize = 20;
+
+/// Words that may precede the definition keyword, e.g. `pub` in `pub fn`.
+const MODIFIERS: &[&str] = &[
+    "pub",
+    "async",
+    "unsafe",
+    "const",
+    "extern",
+    "export",
+    "default",
+    "static",
+    "public",
+    "private",
+    "protected",
+    "internal",
+    "abstract",
+    "final",
+    "override",
+    "local",
+];
+const DEFINITIONS: &[&str] = &[
+    "fn",
+    "def",
+    "function",
+    "func",
+    "class",
+    "impl",
+    "struct",
+    "enum",
+    "trait",
+    "mod",
+    "interface",
+    "module",
+];
+
+/// An inclusive range of 1-based line numbers.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LineRange {
+    pub start_line: usize,
+    pub end_line: usize,
+}
+
+impl LineRange {
+    /// The text of these lines of `text`, each terminated by a newline.
+    pub fn slice(&self, text: &str) -> String {
+        text.lines()
+            .skip(self.start_line - 1)
+            .take(self.end_line + 1 - self.start_line)
+            .map(|line| format!("{}\n", line))
+            .collect()
+    }
+}
+
+/// Finds the definition enclosing the 1-based line `row`, falling back to the lines around it.
+pub fn enclosing(text: &str, row: usize) -> LineRange {
+    let lines = text.lines().collect::<Vec<_>>();
+    if lines.is_empty() {
+        return LineRange {
+            start_line: 1,
+            end_line: 1,
+        };
+    }
+    let cursor = row.clamp(1, lines.len()) - 1;
+
+    // A blank cursor line takes the indentation of the code above it.
+    let cursor_indent = lines[..=cursor]
+        .iter()
+        .rev()
+        .find(|line| !line.trim().is_empty())
+        .map(|line| indent(line))
+        .unwrap_or(0);
+
+    let lowest = cursor.saturating_sub(MAX_SCAN_LINES);
+    let header = (lowest..=cursor)
+        .rev()
+        .find(|&i| indent(lines[i]) <= cursor_indent && is_definition(lines[i]));
+    if let Some(header) = header {
+        let end = definition_end(&lines, header);
+        if end >= cursor {
+            return LineRange {
+                start_line: leading_attributes(&lines, header) + 1,
+                end_line: end + 1,
+            };
+        }
+    }
+
+    LineRange {
+        start_line: cursor.saturating_sub(FALLBACK_LINES) + 1,
+        end_line: (cursor + FALLBACK_LINES).min(lines.len() - 1) + 1,
+    }
+}
+
+/// Finds every definition of `name`, e.g. both `struct Point` and `impl Point`.
+pub fn definitions(text: &str, name: &str) -> Vec<LineRange> {
+    let lines = text.lines().collect::<Vec<_>>();
+    (0..lines.len())
+        .filter(|&i| definition_name(lines[i]) == Some(name))
+        .map(|i| LineRange {
+            start_line: leading_attributes(&lines, i) + 1,
+            end_line: definition_end(&lines, i) + 1,
+        })
+        .collect()
+}
+
+fn indent(line: &str) -> usize {
+    line.len() - line.trim_start().len()
+}
+
+fn is_definition(line: &str) -> bool {
+    definition_name(line).is_some()
+}
+
+/// Returns the name defined by `line`, e.g. `add` for `pub fn add(x: i32)`, if it starts a
+/// definition.
+fn definition_name(line: &str) -> Option<&str> {
+    let mut words = line
+        .trim_start()
+        .split(|c: char| c.is_whitespace() || c == '(' || c == '<' || c == ':' || c == '{')
+        .filter(|w| !w.is_empty());
+    let mut word = words.next()?;
+    // Visibility restrictions like `pub(crate)` are split into `pub` and `crate)`.
+    while MODIFIERS.contains(&word) || word.ends_with(')') {
+        word = words.next()?;
+    }
+    if !DEFINITIONS.contains(&word) {
+        return None;
+    }
+    // `impl` blocks have no name of their own, so they are named after their type.
+    let mut name = words.next()?;
+    if word == "impl" {
+        name = line.split_whitespace().rfind(|w| *w != "{")?;
+    }
+    name.split(|c: char| !c.is_alphanumeric() && c != '_')
+        .next()
+        .filter(|name| !name.is_empty())
+}
+
+/// The last line of the definition starting at `header`: the line before the next line that is
+/// indented no further than the header, or that line itself if it closes the definition.
+fn definition_end(lines: &[&str], header: usize) -> usize {
+    let header_indent = indent(lines[header]);
+    let mut end = header;
+    for (i, line) in lines.iter().enumerate().skip(header + 1) {
+        let trimmed = line.trim();
+        if trimmed.is_empty() {
+            continue;
+        }
+        if indent(line) > header_indent {
+            end = i;
+            continue;
+        }
+        // Continuations of a signature that spans multiple lines, e.g. `) -> T {`.
+        if trimmed.starts_with(')') || trimmed.starts_with(']') {
+            end = i;
+            continue;
+        }
+        let closes = trimmed.starts_with('}') || trimmed == "end" || trimmed.starts_with("end ");
+        if closes && indent(line) == header_indent {
+            end = i;
+        }
+        break;
+    }
+    end
+}
+
+/// The first line of the attributes, decorators and doc comments directly above `header`.
+fn leading_attributes(lines: &[&str], header: usize) -> usize {
+    let header_indent = indent(lines[header]);
+    let mut start = header;
+    while start > 0 {
+        let line = lines[start - 1];
+        let trimmed = line.trim_start();
+        let is_attribute = trimmed.starts_with("#[")
+            || trimmed.starts_with('@')
+            || trimmed.starts_with("///")
+            || trimmed.starts_with("//!");
+        if indent(line) != header_indent || !is_attribute {
+            break;
+        }
+        start -= 1;
+    }
+    start
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RUST: &str = "\
+use std::fmt;
+
+struct Point {
+    x: i32,
+}
+
+impl Point {
+    /// Creates a point.
+    #[inline]
+    pub fn new(
+        x: i32,
+    ) -> Self {
+        let p = Point { x };
+
+        p
+    }
+
+    fn x(&self) -> i32 { self.x }
+}
+";
+
+    const PYTHON: &str = "\
+import os
+
+class Greeter:
+    def greet(self, name):
+        if name:
+            print(name)
+
+        return name
+
+    def leave(self):
+        pass
+";
+
+    fn range(start_line: usize, end_line: usize) -> LineRange {
+        LineRange {
+            start_line,
+            end_line,
+        }
+    }
+
+    #[test]
+    fn test_enclosing() {
+        // Inside a method, including its doc comment and multi-line signature.
+        assert_eq!(enclosing(RUST, 13), range(8, 16));
+        // On a blank line within the method.
+        assert_eq!(enclosing(RUST, 14), range(8, 16));
+        // A one-line method, which does not extend to the end of the `impl`.
+        assert_eq!(enclosing(RUST, 18), range(18, 18));
+        assert_eq!(enclosing(RUST, 7), range(7, 19));
+        assert_eq!(enclosing(RUST, 4), range(3, 5));
+
+        assert_eq!(enclosing(PYTHON, 6), range(4, 8));
+        assert_eq!(enclosing(PYTHON, 11), range(10, 11));
+        // Outside of any definition.
+        assert_eq!(enclosing(PYTHON, 1), range(1, 11));
+    }
+
+    #[test]
+    fn test_definitions() {
+        assert_eq!(definitions(RUST, "Point"), vec![range(3, 5), range(7, 19)]);
+        assert_eq!(definitions(RUST, "new"), vec![range(8, 16)]);
+        assert_eq!(definitions(PYTHON, "leave"), vec![range(10, 11)]);
+        assert!(definitions(PYTHON, "missing").is_empty());
+    }
+
+    #[test]
+    fn test_definition_name() {
+        assert_eq!(
+            definition_name("pub(crate) async fn run<T>(x: T)"),
+            Some("run")
+        );
+        assert_eq!(definition_name("    def greet(self):"), Some("greet"));
+        assert_eq!(
+            definition_name("impl fmt::Display for Point {"),
+            Some("Point")
+        );
+        assert_eq!(definition_name("impl<T> Stack<T> {"), Some("Stack"));
+        assert_eq!(definition_name("class Greeter:"), Some("Greeter"));
+        assert_eq!(definition_name("let x = 1;"), None);
+    }
+
+    #[test]
+    fn test_slice() {
+        assert_eq!(range(3, 5).slice(RUST), "struct Point {\n    x: i32,\n}\n");
+    }
+}
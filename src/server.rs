@@ -0,0 +1,321 @@
+use std::{collections::HashMap, path::Path, sync::Arc};
+
+use clap::Args;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    sync::{mpsc, Mutex},
+    task::AbortHandle,
+};
+use tracing::error;
+
+use crate::chat;
+use crate::code::{self, CodeResponse};
+use crate::context;
+use crate::jsonrpc::{self, ErrorObject, Notification, Request, Response};
+use crate::store::{self, Conversation, ConversationStore, StoreArgs};
+use crate::*;
+
+#[derive(Args, Debug)]
+pub struct ServeArgs {
+    /// Communicate over stdin and stdout, one JSON-RPC message per line.
+    #[arg(long)]
+    stdio: bool,
+    /// The repository whose `.db` conversations are served.
+    #[arg(short, long, default_value = ".")]
+    current_repo_dir: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct ChatSendParams {
+    conversation_id: String,
+    prompt: String,
+    #[serde(default)]
+    context: String,
+    /// Whether to send `delta` notifications while the reply streams in.
+    #[serde(default)]
+    stream: bool,
+}
+
+#[derive(Deserialize, Debug)]
+struct CodeGenerateParams {
+    prompt: String,
+    #[serde(default)]
+    context: String,
+    #[serde(default)]
+    stream: bool,
+}
+
+#[derive(Deserialize, Debug)]
+struct CancelParams {
+    id: Value,
+}
+
+/// Keeps one client and the conversations it has loaded alive across requests, so editors do not
+/// pay for loading the SDK config and history on every request.
+pub async fn execute_serve(
+    args: ServeArgs,
+    store: &StoreArgs,
+    client: Box<dyn AiClient>,
+) -> anyhow::Result<()> {
+    if !args.stdio {
+        anyhow::bail!("only --stdio is currently supported");
+    }
+    let store = store.open(Path::new(&args.current_repo_dir)).await?;
+
+    let (tx, mut rx) = mpsc::unbounded_channel::<String>();
+    let writer = tokio::spawn(async move {
+        let mut stdout = tokio::io::stdout();
+        while let Some(line) = rx.recv().await {
+            stdout.write_all(line.as_bytes()).await?;
+            stdout.write_all(b"\n").await?;
+            stdout.flush().await?;
+        }
+        anyhow::Ok(())
+    });
+
+    let server = Arc::new(Server {
+        client: Arc::from(client),
+        store,
+        conversations: Mutex::new(HashMap::new()),
+        in_flight: std::sync::Mutex::new(HashMap::new()),
+        tx,
+    });
+
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    let mut tasks = Vec::new();
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+        if let Some(task) = server.clone().dispatch(&line).await {
+            tasks.push(task);
+        }
+    }
+
+    // Let in-flight requests finish before exiting on EOF.
+    for task in tasks {
+        let _ = task.await;
+    }
+    drop(server);
+    writer.await??;
+
+    Ok(())
+}
+
+struct Server {
+    client: Arc<dyn AiClient>,
+    store: Arc<dyn ConversationStore>,
+    conversations: Mutex<HashMap<String, Arc<Mutex<Conversation>>>>,
+    /// Abort handles of spawned requests, keyed by their serialized id.
+    in_flight: std::sync::Mutex<HashMap<String, AbortHandle>>,
+    tx: mpsc::UnboundedSender<String>,
+}
+
+impl Server {
+    fn send<T: serde::Serialize>(&self, message: &T) {
+        match serde_json::to_string(message) {
+            Ok(line) => {
+                let _ = self.tx.send(line);
+            }
+            Err(e) => error!("Unable to serialize message: {}", e),
+        }
+    }
+
+    /// Handles a single line, returning the task if the request was spawned in the background.
+    async fn dispatch(self: Arc<Self>, line: &str) -> Option<tokio::task::JoinHandle<()>> {
+        let request = match serde_json::from_str::<Request>(line) {
+            Ok(request) => request,
+            Err(e) => {
+                let code = if serde_json::from_str::<Value>(line).is_ok() {
+                    jsonrpc::INVALID_REQUEST
+                } else {
+                    jsonrpc::PARSE_ERROR
+                };
+                self.send(&Response::error(Value::Null, code, e.to_string()));
+                return None;
+            }
+        };
+        debug!("Received request: {:?}", request);
+
+        let Some(id) = request.id else {
+            // Notifications never get a response.
+            if request.method == "cancel" {
+                let _ = self.cancel(request.params);
+            }
+            return None;
+        };
+
+        match request.method.as_str() {
+            "cancel" => {
+                let result = self.cancel(request.params);
+                self.respond(id, result);
+                None
+            }
+            "conversation/list" => {
+                let result = self.list_conversations().await;
+                self.respond(id, result);
+                None
+            }
+            "chat/send" | "code/generate" => Some(self.spawn(id, request.method, request.params)),
+            method => {
+                self.send(&Response::error(
+                    id,
+                    jsonrpc::METHOD_NOT_FOUND,
+                    format!("unknown method: {}", method),
+                ));
+                None
+            }
+        }
+    }
+
+    fn respond(&self, id: Value, result: Result<Value, ErrorObject>) {
+        match result {
+            Ok(result) => self.send(&Response::result(id, result)),
+            Err(e) => self.send(&Response::error(id, e.code, e.message)),
+        }
+    }
+
+    /// Runs a model request in the background so that it can be cancelled.
+    fn spawn(
+        self: Arc<Self>,
+        id: Value,
+        method: String,
+        params: Value,
+    ) -> tokio::task::JoinHandle<()> {
+        let key = id.to_string();
+        let server = self.clone();
+        let task_id = id.clone();
+        let mut in_flight = self.in_flight.lock().unwrap();
+        let task = tokio::spawn(async move {
+            let result = match method.as_str() {
+                "chat/send" => server.chat_send(&task_id, params).await,
+                _ => server.code_generate(&task_id, params).await,
+            };
+            // A request that is no longer in flight has already been answered by `cancel`.
+            let removed = server
+                .in_flight
+                .lock()
+                .unwrap()
+                .remove(&task_id.to_string());
+            if removed.is_some() {
+                server.respond(task_id, result);
+            }
+        });
+        in_flight.insert(key, task.abort_handle());
+        task
+    }
+
+    fn cancel(&self, params: Value) -> Result<Value, ErrorObject> {
+        let params = jsonrpc::params::<CancelParams>(params)?;
+        let handle = self
+            .in_flight
+            .lock()
+            .unwrap()
+            .remove(&params.id.to_string());
+        let cancelled = match handle {
+            Some(handle) => {
+                handle.abort();
+                self.send(&Response::error(
+                    params.id,
+                    jsonrpc::REQUEST_CANCELLED,
+                    "request cancelled",
+                ));
+                true
+            }
+            None => false,
+        };
+        Ok(json!({ "cancelled": cancelled }))
+    }
+
+    async fn list_conversations(&self) -> Result<Value, ErrorObject> {
+        let mut ids = self
+            .store
+            .list()
+            .await
+            .map_err(internal_error)?
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect::<Vec<_>>();
+        ids.sort();
+        Ok(json!({ "conversations": ids }))
+    }
+
+    async fn conversation(&self, id: &str) -> Result<Arc<Mutex<Conversation>>, ErrorObject> {
+        store::validate_id(id).map_err(|e| ErrorObject {
+            code: jsonrpc::INVALID_PARAMS,
+            message: e.to_string(),
+        })?;
+
+        let mut conversations = self.conversations.lock().await;
+        if let Some(conversation) = conversations.get(id) {
+            return Ok(conversation.clone());
+        }
+        let conversation = Conversation::open(self.store.clone(), id)
+            .await
+            .map_err(internal_error)?;
+        let conversation = Arc::new(Mutex::new(conversation));
+        conversations.insert(id.to_string(), conversation.clone());
+        Ok(conversation)
+    }
+
+    /// Forwards deltas as notifications when the client asked to stream.
+    fn on_delta(&self, id: &Value, stream: bool) -> impl for<'d> FnMut(&'d str) + Send {
+        let tx = self.tx.clone();
+        let id = id.clone();
+        move |delta| {
+            if stream {
+                let notification =
+                    Notification::new("delta", json!({ "request_id": id, "text": delta }));
+                if let Ok(line) = serde_json::to_string(&notification) {
+                    let _ = tx.send(line);
+                }
+            }
+        }
+    }
+
+    async fn chat_send(&self, id: &Value, params: Value) -> Result<Value, ErrorObject> {
+        let params = jsonrpc::params::<ChatSendParams>(params)?;
+        let conversation = self.conversation(&params.conversation_id).await?;
+        let mut conversation = conversation.lock().await;
+        let response = chat::send(
+            self.client.as_ref(),
+            &mut conversation,
+            Message {
+                prompt: params.prompt,
+                free_context: context::render_active_buffer(params.context),
+                attachments: Vec::new(),
+            },
+            None,
+            &mut self.on_delta(id, params.stream),
+        )
+        .await
+        .map_err(internal_error)?;
+        Ok(json!({ "message": response.message, "usage": response.usage }))
+    }
+
+    async fn code_generate(&self, id: &Value, params: Value) -> Result<Value, ErrorObject> {
+        let params = jsonrpc::params::<CodeGenerateParams>(params)?;
+        let (code_objects, usage) = code::generate(
+            self.client.as_ref(),
+            &params.prompt,
+            &context::render_active_buffer(params.context),
+            Vec::new(),
+            &mut self.on_delta(id, params.stream),
+        )
+        .await
+        .map_err(internal_error)?;
+        let mut result =
+            serde_json::to_value(CodeResponse::new(code_objects)).map_err(internal_error)?;
+        result["usage"] = json!(usage);
+        Ok(result)
+    }
+}
+
+fn internal_error(e: impl std::fmt::Display) -> ErrorObject {
+    ErrorObject {
+        code: jsonrpc::INTERNAL_ERROR,
+        message: e.to_string(),
+    }
+}
@@ -0,0 +1,188 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+
+use super::{sqlite, validate_id, ConversationLock, ConversationStore, Metadata, Record};
+use crate::message::SCHEMA_VERSION;
+use crate::StorableMessage;
+
+/// Stores each conversation as a JSON file, `.db/<id>`, rewriting the whole file on every change.
+pub struct JsonStore {
+    db_path: PathBuf,
+}
+
+/// The on-disk formats of a conversation, which used to be just its messages. Records written
+/// before messages were versioned are version 1.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredConversation {
+    Messages(Vec<StorableMessage>),
+    Record {
+        #[serde(default = "first_version")]
+        version: u32,
+        #[serde(default)]
+        metadata: Option<Metadata>,
+        messages: Vec<StorableMessage>,
+        #[serde(default)]
+        archive: Vec<StorableMessage>,
+    },
+}
+
+fn first_version() -> u32 {
+    1
+}
+
+/// A conversation as written, with the version of its message format.
+#[derive(Serialize)]
+struct VersionedRecord<'a> {
+    version: u32,
+    #[serde(flatten)]
+    record: &'a Record,
+}
+
+impl JsonStore {
+    pub fn new(db_path: PathBuf) -> Self {
+        Self { db_path }
+    }
+
+    fn path(&self, id: &str) -> anyhow::Result<PathBuf> {
+        validate_id(id)?;
+        Ok(self.db_path.join(id))
+    }
+
+    /// Where a conversation is written before being renamed over `path`, so that a crash never
+    /// leaves it half written.
+    fn temp_path(&self, id: &str) -> PathBuf {
+        self.db_path.join(format!(".{}.tmp", id))
+    }
+
+    /// Whether `path` is a conversation, rather than something else kept under `.db`, like the
+    /// hidden lock and temporary files.
+    pub(super) async fn is_conversation(path: &Path) -> anyhow::Result<bool> {
+        let is_other = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.starts_with(sqlite::FILE_NAME) || name.starts_with('.'));
+        Ok(!is_other && tokio::fs::metadata(path).await?.is_file())
+    }
+
+    /// Reads the conversation stored at `path`. Conversations from before metadata was stored are
+    /// dated by their file.
+    ///
+    /// A file that cannot be parsed is an error rather than an empty conversation, since the next
+    /// reply would otherwise overwrite it.
+    pub(super) async fn read(path: &Path) -> anyhow::Result<Record> {
+        let json = tokio::fs::read_to_string(path).await?;
+        let modified: DateTime<Utc> = tokio::fs::metadata(path).await?.modified()?.into();
+        // Older versions created the file before anything was sent.
+        if json.trim().is_empty() {
+            return Ok(Record {
+                metadata: Metadata::new(modified),
+                messages: Vec::new(),
+                archive: Vec::new(),
+            });
+        }
+        let stored = serde_json::from_str::<StoredConversation>(&json)
+            .with_context(|| format!("unable to parse conversation {}", path.display()))?;
+        Ok(match stored {
+            StoredConversation::Messages(messages) => Record {
+                metadata: Metadata::new(modified),
+                messages,
+                archive: Vec::new(),
+            },
+            StoredConversation::Record { version, .. } if version > SCHEMA_VERSION => {
+                anyhow::bail!(
+                    "conversation {} was written by a newer version of hackathon",
+                    path.display()
+                )
+            }
+            StoredConversation::Record {
+                metadata,
+                messages,
+                archive,
+                ..
+            } => Record {
+                metadata: metadata.unwrap_or_else(|| Metadata::new(modified)),
+                messages,
+                archive,
+            },
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl ConversationStore for JsonStore {
+    async fn load(&self, id: &str) -> anyhow::Result<Option<Record>> {
+        let path = self.path(id)?;
+        if !path.is_file() {
+            return Ok(None);
+        }
+        Ok(Some(Self::read(&path).await?))
+    }
+
+    async fn list(&self) -> anyhow::Result<Vec<(String, Record)>> {
+        let mut conversations = Vec::new();
+        let mut entries = tokio::fs::read_dir(&self.db_path).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if Self::is_conversation(&path).await? {
+                let id = entry.file_name().to_string_lossy().to_string();
+                conversations.push((id, Self::read(&path).await?));
+            }
+        }
+        Ok(conversations)
+    }
+
+    async fn append(
+        &self,
+        id: &str,
+        metadata: &Metadata,
+        messages: &[StorableMessage],
+    ) -> anyhow::Result<()> {
+        let mut record = match self.load(id).await? {
+            Some(record) => record,
+            None => Record {
+                metadata: metadata.clone(),
+                messages: Vec::new(),
+                archive: Vec::new(),
+            },
+        };
+        record.metadata = metadata.clone();
+        record.messages.extend_from_slice(messages);
+        self.save(id, &record).await
+    }
+
+    async fn save(&self, id: &str, record: &Record) -> anyhow::Result<()> {
+        let path = self.path(id)?;
+        let temp_path = self.temp_path(id);
+        let json = serde_json::to_string_pretty(&VersionedRecord {
+            version: SCHEMA_VERSION,
+            record,
+        })?;
+        let mut file = tokio::fs::File::create(&temp_path).await?;
+        file.write_all(json.as_bytes()).await?;
+        file.sync_all().await?;
+        tokio::fs::rename(&temp_path, &path).await?;
+        Ok(())
+    }
+
+    async fn save_metadata(&self, id: &str, metadata: &Metadata) -> anyhow::Result<()> {
+        let Some(mut record) = self.load(id).await? else {
+            anyhow::bail!("no conversation with id {}", id);
+        };
+        record.metadata = metadata.clone();
+        self.save(id, &record).await
+    }
+
+    async fn lock(&self, id: &str) -> anyhow::Result<ConversationLock> {
+        ConversationLock::acquire(&self.db_path, id).await
+    }
+
+    async fn delete(&self, id: &str) -> anyhow::Result<()> {
+        tokio::fs::remove_file(self.path(id)?).await?;
+        Ok(())
+    }
+}
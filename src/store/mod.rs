@@ -0,0 +1,603 @@
+//! Where conversations are kept between runs, under the repository's `.db` directory.
+
+mod json;
+mod sqlite;
+
+pub use json::JsonStore;
+pub use sqlite::SqliteStore;
+
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use chrono::{DateTime, Utc};
+use clap::{Args, ValueEnum};
+use serde::{Deserialize, Serialize};
+
+use crate::StorableMessage;
+
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum StoreKind {
+    /// A single SQLite database, `.db/conversations.sqlite3`.
+    #[default]
+    Sqlite,
+    /// One JSON file per conversation, `.db/<id>`.
+    Json,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct StoreArgs {
+    /// How conversations are stored. The first time the SQLite store is used, conversations from
+    /// the JSON store are imported into it and moved to `.db/imported`.
+    #[arg(
+        long,
+        global = true,
+        value_enum,
+        default_value_t,
+        env = "HACKATHON_STORE"
+    )]
+    pub store: StoreKind,
+}
+
+impl StoreArgs {
+    /// Opens the store under `current_repo_dir/.db`, creating it if needed.
+    pub async fn open(
+        &self,
+        current_repo_dir: &Path,
+    ) -> anyhow::Result<Arc<dyn ConversationStore>> {
+        if !current_repo_dir.is_dir() {
+            anyhow::bail!("current repo directory given is invalid");
+        }
+        let db_path = current_repo_dir.join(".db");
+        if !db_path.is_dir() {
+            tokio::fs::create_dir(&db_path).await?;
+        }
+        Ok(match self.store {
+            StoreKind::Sqlite => Arc::new(SqliteStore::open(&db_path).await?),
+            StoreKind::Json => Arc::new(JsonStore::new(db_path)),
+        })
+    }
+}
+
+/// Details about a conversation, stored alongside its messages.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Metadata {
+    /// Defaults to the start of the first prompt.
+    pub title: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    /// The model that replied last.
+    pub model: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub forked_from: Option<Fork>,
+}
+
+/// Where a conversation was forked from.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Fork {
+    pub id: String,
+    /// How many turns were copied from it.
+    pub turn: usize,
+}
+
+impl Metadata {
+    fn new(created_at: DateTime<Utc>) -> Self {
+        Self {
+            title: None,
+            created_at,
+            updated_at: created_at,
+            model: None,
+            forked_from: None,
+        }
+    }
+}
+
+/// Everything stored about a conversation.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Record {
+    pub metadata: Metadata,
+    pub messages: Vec<StorableMessage>,
+    /// Turns that have been replaced by a summary in `messages`, oldest first.
+    #[serde(default)]
+    pub archive: Vec<StorableMessage>,
+}
+
+#[async_trait::async_trait]
+pub trait ConversationStore: Send + Sync {
+    /// Loads conversation `id`, if it has been stored.
+    async fn load(&self, id: &str) -> anyhow::Result<Option<Record>>;
+
+    /// Loads every stored conversation, keyed by id.
+    async fn list(&self) -> anyhow::Result<Vec<(String, Record)>>;
+
+    /// Appends `messages` to conversation `id`, creating it if needed, and updates its metadata.
+    /// Messages appended by someone else since it was loaded are kept.
+    async fn append(
+        &self,
+        id: &str,
+        metadata: &Metadata,
+        messages: &[StorableMessage],
+    ) -> anyhow::Result<()>;
+
+    /// Replaces everything stored about conversation `id` with `record`.
+    async fn save(&self, id: &str, record: &Record) -> anyhow::Result<()>;
+
+    /// Replaces the metadata of conversation `id`, leaving its messages as they are.
+    async fn save_metadata(&self, id: &str, metadata: &Metadata) -> anyhow::Result<()>;
+
+    async fn delete(&self, id: &str) -> anyhow::Result<()>;
+
+    /// Keeps other processes from changing conversation `id` until the returned lock is dropped,
+    /// so that it can be read, changed and saved as a whole.
+    async fn lock(&self, id: &str) -> anyhow::Result<ConversationLock>;
+}
+
+/// An advisory lock on a conversation, released when dropped. Lock files are never removed, not
+/// even when their conversation is deleted, as another process may be waiting on the same file.
+pub struct ConversationLock {
+    _file: std::fs::File,
+}
+
+impl ConversationLock {
+    /// Where the lock on conversation `id` is kept under `db_path`.
+    fn path(db_path: &Path, id: &str) -> PathBuf {
+        db_path.join(format!(".{}.lock", id))
+    }
+
+    /// Takes an exclusive lock on conversation `id`, waiting for as long as another process holds
+    /// it, e.g. while replying in the same conversation.
+    async fn acquire(db_path: &Path, id: &str) -> anyhow::Result<Self> {
+        validate_id(id)?;
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(Self::path(db_path, id))?;
+        let file = tokio::task::spawn_blocking(move || file.lock().map(|()| file)).await??;
+        Ok(Self { _file: file })
+    }
+}
+
+/// Checks a conversation id, which comes from editors and clients, before it is used as a key or
+/// file name.
+pub fn validate_id(id: &str) -> anyhow::Result<()> {
+    // Hidden files are left for the stores' own use.
+    if id.is_empty() || id.starts_with('.') || id.contains(['/', '\\']) {
+        anyhow::bail!("invalid conversation id {:?}", id);
+    }
+    Ok(())
+}
+
+/// The message history of a single chat, along with the store it is kept in.
+pub struct Conversation {
+    id: String,
+    store: Arc<dyn ConversationStore>,
+    pub metadata: Metadata,
+    messages: Vec<StorableMessage>,
+    archive: Vec<StorableMessage>,
+    /// When to compact the conversation, see [`crate::chat::compact`].
+    pub compact_after_tokens: Option<usize>,
+}
+
+impl std::fmt::Debug for Conversation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Conversation")
+            .field("id", &self.id)
+            .field("metadata", &self.metadata)
+            .field("messages", &self.messages.len())
+            .finish()
+    }
+}
+
+impl Conversation {
+    /// Opens conversation `id`, starting an empty one if it has not been stored yet.
+    pub async fn open(store: Arc<dyn ConversationStore>, id: &str) -> anyhow::Result<Self> {
+        validate_id(id)?;
+        let record = store.load(id).await?.unwrap_or_else(|| Record {
+            metadata: Metadata::new(Utc::now()),
+            messages: Vec::new(),
+            archive: Vec::new(),
+        });
+        Ok(Self::from_record(store, id.to_string(), record))
+    }
+
+    /// Opens conversation `id`, failing if it has not been stored.
+    pub async fn find(store: Arc<dyn ConversationStore>, id: &str) -> anyhow::Result<Self> {
+        validate_id(id)?;
+        match store.load(id).await? {
+            Some(record) => Ok(Self::from_record(store, id.to_string(), record)),
+            None => anyhow::bail!("no conversation with id {}", id),
+        }
+    }
+
+    /// Opens every stored conversation.
+    pub async fn list(store: Arc<dyn ConversationStore>) -> anyhow::Result<Vec<Self>> {
+        Ok(store
+            .list()
+            .await?
+            .into_iter()
+            .map(|(id, record)| Self::from_record(store.clone(), id, record))
+            .collect())
+    }
+
+    fn from_record(store: Arc<dyn ConversationStore>, id: String, record: Record) -> Self {
+        Self {
+            id,
+            store,
+            metadata: record.metadata,
+            messages: record.messages,
+            archive: record.archive,
+            compact_after_tokens: None,
+        }
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn messages(&self) -> &[StorableMessage] {
+        &self.messages
+    }
+
+    pub fn archive(&self) -> &[StorableMessage] {
+        &self.archive
+    }
+
+    /// Locks the conversation against changes from other processes until the returned lock is
+    /// dropped, and reloads it in case it was changed before the lock was taken.
+    pub async fn lock(&mut self) -> anyhow::Result<ConversationLock> {
+        let lock = self.store.lock(&self.id).await?;
+        if let Some(record) = self.store.load(&self.id).await? {
+            self.metadata = record.metadata;
+            self.messages = record.messages;
+            self.archive = record.archive;
+        }
+        Ok(lock)
+    }
+
+    /// How many turns, i.e. prompts and their replies, the conversation has.
+    pub fn turns(&self) -> usize {
+        self.messages.iter().filter(|m| m.role == "user").count()
+    }
+
+    /// Copies this conversation into a new conversation `id`, up to and including `turn` if
+    /// given, and stores it.
+    pub async fn fork(&self, id: &str, turn: Option<usize>) -> anyhow::Result<Conversation> {
+        let turns = self.turns();
+        let turn = turn.unwrap_or(turns);
+        if turn == 0 || turn > turns {
+            anyhow::bail!(
+                "conversation {} has {} turns, so it cannot be forked at turn {}",
+                self.id,
+                turns,
+                turn
+            );
+        }
+        // Held until the fork is stored, so that it cannot be created twice at once.
+        let _lock = self.store.lock(id).await?;
+        let mut fork = Conversation::open(self.store.clone(), id).await?;
+        if !fork.messages.is_empty() {
+            anyhow::bail!("conversation {} already exists", id);
+        }
+
+        // Everything before the prompt of the next turn.
+        let end = self
+            .messages
+            .iter()
+            .enumerate()
+            .filter(|(_, m)| m.role == "user")
+            .nth(turn)
+            .map_or(self.messages.len(), |(i, _)| i);
+        fork.messages = self.messages[..end].to_vec();
+        fork.archive = self.archive.clone();
+        fork.metadata.title = self.metadata.title.clone();
+        fork.metadata.model = self.metadata.model.clone();
+        fork.metadata.forked_from = Some(Fork {
+            id: self.id.clone(),
+            turn,
+        });
+        fork.store().await?;
+        Ok(fork)
+    }
+
+    /// Appends `messages` and stores them along with the metadata.
+    pub async fn append(&mut self, messages: Vec<StorableMessage>) -> anyhow::Result<()> {
+        self.store
+            .append(&self.id, &self.metadata, &messages)
+            .await?;
+        self.messages.extend(messages);
+        Ok(())
+    }
+
+    /// Moves the `count` oldest messages to the archive, putting `replacement` in their place.
+    /// Only takes effect in the store once [`Conversation::store`] is called.
+    pub fn archive_oldest(
+        &mut self,
+        count: usize,
+        replacement: impl IntoIterator<Item = StorableMessage>,
+    ) {
+        let archived = self.messages.splice(..count, replacement);
+        self.archive.extend(archived);
+    }
+
+    /// Stores only the metadata, keeping any messages appended by someone else since it was
+    /// loaded.
+    pub async fn store_metadata(&self) -> anyhow::Result<()> {
+        self.store.save_metadata(&self.id, &self.metadata).await
+    }
+
+    /// Stores the whole conversation, replacing what was stored before. The conversation should
+    /// be locked since it was loaded, so that nothing appended in the meantime is lost.
+    pub async fn store(&self) -> anyhow::Result<()> {
+        let record = Record {
+            metadata: self.metadata.clone(),
+            messages: self.messages.clone(),
+            archive: self.archive.clone(),
+        };
+        self.store.save(&self.id, &record).await
+    }
+
+    pub async fn delete(self) -> anyhow::Result<()> {
+        self.store.delete(&self.id).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(role: &str, content: &str) -> StorableMessage {
+        StorableMessage::new(role, content)
+    }
+
+    /// Runs the same checks against every kind of store.
+    async fn check_store(store: Arc<dyn ConversationStore>) {
+        let mut conversation = Conversation::open(store.clone(), "1").await.unwrap();
+        assert!(conversation.messages().is_empty());
+        assert!(Conversation::find(store.clone(), "1").await.is_err());
+        assert!(Conversation::open(store.clone(), "../1").await.is_err());
+
+        conversation
+            .append(vec![message("user", "hi"), message("assistant", "hello")])
+            .await
+            .unwrap();
+        // Appends from another conversation loaded at the same time are not lost.
+        let mut other = Conversation::find(store.clone(), "1").await.unwrap();
+        let mut bye = message("user", "bye");
+        bye.created_at = Some("2026-03-01T12:00:00Z".parse().unwrap());
+        other
+            .append(vec![bye.clone(), message("assistant", "goodbye")])
+            .await
+            .unwrap();
+        conversation
+            .append(vec![message("user", "again"), message("assistant", "sure")])
+            .await
+            .unwrap();
+        let loaded = Conversation::find(store.clone(), "1").await.unwrap();
+        assert_eq!(loaded.messages().len(), 6);
+        assert_eq!(loaded.messages()[2], bye);
+
+        let mut loaded = loaded;
+        loaded.archive_oldest(4, [message("user", "summary"), message("assistant", "ok")]);
+        loaded.metadata.title = Some("greetings".into());
+        loaded.store().await.unwrap();
+        let loaded = Conversation::find(store.clone(), "1").await.unwrap();
+        assert_eq!(loaded.metadata.title.as_deref(), Some("greetings"));
+        assert_eq!(loaded.messages().len(), 4);
+        assert_eq!(loaded.archive().len(), 4);
+
+        let ids = Conversation::list(store.clone())
+            .await
+            .unwrap()
+            .iter()
+            .map(|c| c.id().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(ids, vec!["1"]);
+
+        loaded.delete().await.unwrap();
+        assert!(Conversation::list(store).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_json_store() {
+        let dir = tempfile::tempdir().unwrap();
+        check_store(Arc::new(JsonStore::new(dir.path().to_path_buf()))).await;
+    }
+
+    #[tokio::test]
+    async fn test_fork() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Arc::new(JsonStore::new(dir.path().to_path_buf()));
+        let mut conversation = Conversation::open(store.clone(), "1").await.unwrap();
+        conversation
+            .append(vec![
+                message("user", "hi"),
+                message("assistant", "hello"),
+                message("user", "bye"),
+                message("assistant", "goodbye"),
+            ])
+            .await
+            .unwrap();
+
+        let fork = conversation.fork("2", Some(1)).await.unwrap();
+        assert_eq!(fork.messages().len(), 2);
+        let fork = Conversation::find(store.clone(), "2").await.unwrap();
+        assert_eq!(
+            fork.metadata.forked_from,
+            Some(Fork {
+                id: "1".into(),
+                turn: 1
+            })
+        );
+        assert_eq!(conversation.fork("3", None).await.unwrap().turns(), 2);
+
+        assert!(conversation.fork("4", Some(3)).await.is_err());
+        assert!(conversation.fork("4", Some(0)).await.is_err());
+        // Forks never overwrite an existing conversation.
+        assert!(conversation.fork("2", Some(2)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_json_store_reads_message_list() {
+        let dir = tempfile::tempdir().unwrap();
+        let messages = vec![message("user", "hi"), message("assistant", "hello")];
+        tokio::fs::write(
+            dir.path().join("1"),
+            serde_json::to_string(&messages).unwrap(),
+        )
+        .await
+        .unwrap();
+
+        let store = Arc::new(JsonStore::new(dir.path().to_path_buf()));
+        let conversation = Conversation::find(store, "1").await.unwrap();
+        assert_eq!(conversation.messages(), messages);
+        assert!(conversation.archive().is_empty());
+        assert_eq!(conversation.metadata.title, None);
+    }
+
+    #[tokio::test]
+    async fn test_json_store_migrates_text_messages() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("1");
+        let record = r#"{"metadata":null,"messages":[{"role":"user","content":"hi"}]}"#;
+        tokio::fs::write(&path, record).await.unwrap();
+
+        let store = Arc::new(JsonStore::new(dir.path().to_path_buf()));
+        let conversation = Conversation::find(store.clone(), "1").await.unwrap();
+        assert_eq!(conversation.messages(), [message("user", "hi")]);
+        conversation.store().await.unwrap();
+        let json: serde_json::Value =
+            serde_json::from_str(&tokio::fs::read_to_string(&path).await.unwrap()).unwrap();
+        assert_eq!(json["version"], 3);
+        assert_eq!(json["messages"][0]["content"][0]["text"], "hi");
+
+        let record = r#"{"version":4,"messages":[]}"#;
+        tokio::fs::write(&path, record).await.unwrap();
+        let e = Conversation::find(store, "1").await.unwrap_err();
+        assert!(e.to_string().contains("newer version"));
+    }
+
+    #[tokio::test]
+    async fn test_json_store_keeps_corrupt_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("1");
+        tokio::fs::write(&path, "[{\"role\":").await.unwrap();
+
+        let store = Arc::new(JsonStore::new(dir.path().to_path_buf()));
+        let e = Conversation::open(store.clone(), "1").await.unwrap_err();
+        assert!(e.to_string().contains("unable to parse conversation"));
+        assert!(Conversation::list(store).await.is_err());
+        assert_eq!(
+            tokio::fs::read_to_string(&path).await.unwrap(),
+            "[{\"role\":"
+        );
+    }
+
+    /// Checks that a conversation changed as a whole under its lock keeps what another writer
+    /// appends at the same time, and that the other writer waits for the lock.
+    async fn check_lock(store: Arc<dyn ConversationStore>) {
+        let mut conversation = Conversation::open(store.clone(), "1").await.unwrap();
+        conversation
+            .append(vec![message("user", "hi"), message("assistant", "hello")])
+            .await
+            .unwrap();
+        let mut other = Conversation::find(store.clone(), "1").await.unwrap();
+
+        let lock = conversation.lock().await.unwrap();
+        let wait = tokio::spawn(async move {
+            let _lock = other.lock().await.unwrap();
+            other
+                .append(vec![
+                    message("user", "bye"),
+                    message("assistant", "goodbye"),
+                ])
+                .await
+                .unwrap();
+        });
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert!(!wait.is_finished());
+        conversation.archive_oldest(2, [message("user", "summary"), message("assistant", "ok")]);
+        conversation.store().await.unwrap();
+        drop(lock);
+        wait.await.unwrap();
+
+        let loaded = Conversation::find(store.clone(), "1").await.unwrap();
+        assert_eq!(loaded.archive().len(), 2);
+        assert_eq!(
+            loaded.messages(),
+            [
+                message("user", "summary"),
+                message("assistant", "ok"),
+                message("user", "bye"),
+                message("assistant", "goodbye"),
+            ]
+        );
+
+        // Renaming only touches the metadata, so it cannot lose messages either.
+        let mut renamed = Conversation::find(store.clone(), "1").await.unwrap();
+        let mut other = Conversation::find(store.clone(), "1").await.unwrap();
+        other
+            .append(vec![message("user", "again"), message("assistant", "sure")])
+            .await
+            .unwrap();
+        renamed.metadata.title = Some("greetings".into());
+        renamed.store_metadata().await.unwrap();
+        let loaded = Conversation::find(store, "1").await.unwrap();
+        assert_eq!(loaded.metadata.title.as_deref(), Some("greetings"));
+        assert_eq!(loaded.messages().len(), 6);
+        loaded.delete().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_store_lock() {
+        let dir = tempfile::tempdir().unwrap();
+        check_lock(Arc::new(SqliteStore::open(dir.path()).await.unwrap())).await;
+        assert!(dir.path().join(".1.lock").exists());
+    }
+
+    #[tokio::test]
+    async fn test_json_store_lock() {
+        let dir = tempfile::tempdir().unwrap();
+        check_lock(Arc::new(JsonStore::new(dir.path().to_path_buf()))).await;
+        assert!(dir.path().join(".1.lock").exists());
+
+        let dir = tempfile::tempdir().unwrap();
+        let store = Arc::new(JsonStore::new(dir.path().to_path_buf()));
+        let mut conversation = Conversation::open(store.clone(), "1").await.unwrap();
+        let lock = conversation.lock().await.unwrap();
+
+        // Another writer, as if from another process, waits for the lock.
+        let mut other = Conversation::open(store.clone(), "1").await.unwrap();
+        let wait = tokio::spawn(async move {
+            let _lock = other.lock().await.unwrap();
+            other.messages().len()
+        });
+        conversation
+            .append(vec![message("user", "hi"), message("assistant", "hello")])
+            .await
+            .unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert!(!wait.is_finished());
+
+        // Once it has the lock, it sees what was appended in the meantime.
+        drop(lock);
+        assert_eq!(wait.await.unwrap(), 2);
+
+        // Only the conversation itself is left behind, not its temporary file.
+        let ids = Conversation::list(store)
+            .await
+            .unwrap()
+            .iter()
+            .map(|c| c.id().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(ids, vec!["1"]);
+        assert!(!dir.path().join(".1.tmp").exists());
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_store() {
+        let dir = tempfile::tempdir().unwrap();
+        check_store(Arc::new(SqliteStore::open(dir.path()).await.unwrap())).await;
+    }
+}
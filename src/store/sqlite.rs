@@ -0,0 +1,426 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use rusqlite::{
+    params, types::Type, Connection, OptionalExtension, Transaction, TransactionBehavior,
+};
+use tracing::{info, warn};
+
+use super::{validate_id, ConversationLock, ConversationStore, Fork, JsonStore, Metadata, Record};
+use crate::StorableMessage;
+
+/// The database file under `.db`.
+pub(super) const FILE_NAME: &str = "conversations.sqlite3";
+/// Where JSON conversations are moved to once imported, under `.db`.
+const IMPORTED_DIR: &str = "imported";
+/// How long to wait for another process writing to the database.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Migrations from each schema version to the next, tracked in `PRAGMA user_version`.
+const MIGRATIONS: &[&str] = &[
+    "
+    CREATE TABLE conversations (
+        id TEXT PRIMARY KEY,
+        title TEXT,
+        created_at TEXT NOT NULL,
+        updated_at TEXT NOT NULL,
+        model TEXT
+    );
+    CREATE INDEX conversations_by_updated_at ON conversations (updated_at);
+    CREATE TABLE messages (
+        conversation_id TEXT NOT NULL REFERENCES conversations (id) ON DELETE CASCADE,
+        archived INTEGER NOT NULL,
+        position INTEGER NOT NULL,
+        role TEXT NOT NULL,
+        content TEXT NOT NULL,
+        PRIMARY KEY (conversation_id, archived, position)
+    );
+",
+    "
+    ALTER TABLE conversations ADD COLUMN forked_from TEXT;
+    ALTER TABLE conversations ADD COLUMN forked_at_turn INTEGER;
+    CREATE INDEX conversations_by_forked_from ON conversations (forked_from);
+",
+    // Messages are stored as their JSON content blocks, see `message::SCHEMA_VERSION`.
+    "
+    ALTER TABLE messages RENAME COLUMN content TO blocks;
+    UPDATE messages SET blocks = json_array(json_object('type', 'text', 'text', blocks));
+",
+    "
+    ALTER TABLE messages ADD COLUMN created_at TEXT;
+",
+];
+
+/// Stores every conversation in one SQLite database, `.db/conversations.sqlite3`. Each change is
+/// a transaction, so concurrent invocations append to a conversation instead of overwriting it.
+/// Conversations are locked with the same `.db/.<id>.lock` files as the JSON store.
+pub struct SqliteStore {
+    db_path: PathBuf,
+    connection: Arc<Mutex<Connection>>,
+}
+
+impl SqliteStore {
+    /// Opens the database under `db_path`, creating it if needed. When it is created, the JSON
+    /// conversations next to it are imported and then moved to `.db/imported`.
+    pub async fn open(db_path: &Path) -> anyhow::Result<Self> {
+        let mut connection = Connection::open(db_path.join(FILE_NAME))?;
+        connection.busy_timeout(BUSY_TIMEOUT)?;
+        connection.pragma_update(None, "foreign_keys", true)?;
+
+        let version: usize =
+            connection.pragma_query_value(None, "user_version", |row| row.get(0))?;
+        if version < MIGRATIONS.len() {
+            // Read before starting the transaction, so that other processes are not kept waiting.
+            let json = if version == 0 {
+                read_json_conversations(db_path).await?
+            } else {
+                Vec::new()
+            };
+
+            let tx = connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            // Another process may have migrated in the meantime.
+            let version: usize = tx.pragma_query_value(None, "user_version", |row| row.get(0))?;
+            for migration in MIGRATIONS.iter().skip(version) {
+                tx.execute_batch(migration)?;
+            }
+            let imported = if version == 0 {
+                for (id, record) in &json {
+                    save(&tx, id, record)?;
+                }
+                json.iter().map(|(id, _)| id.clone()).collect()
+            } else {
+                Vec::new()
+            };
+            tx.pragma_update(None, "user_version", MIGRATIONS.len())?;
+            tx.commit()?;
+
+            if !imported.is_empty() {
+                info!("Imported {} JSON conversations", imported.len());
+                let imported_path = db_path.join(IMPORTED_DIR);
+                tokio::fs::create_dir_all(&imported_path).await?;
+                for id in imported {
+                    tokio::fs::rename(db_path.join(&id), imported_path.join(&id)).await?;
+                }
+            }
+        }
+
+        Ok(Self {
+            db_path: db_path.to_path_buf(),
+            connection: Arc::new(Mutex::new(connection)),
+        })
+    }
+
+    /// Runs `f` on the connection without blocking the runtime.
+    async fn with<T, F>(&self, f: F) -> anyhow::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> rusqlite::Result<T> + Send + 'static,
+    {
+        let connection = self.connection.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut connection = connection
+                .lock()
+                .map_err(|_| anyhow::anyhow!("the conversation database is poisoned"))?;
+            Ok(f(&mut connection)?)
+        })
+        .await?
+    }
+}
+
+/// Reads the JSON conversations under `db_path`, skipping those that cannot be read.
+async fn read_json_conversations(db_path: &Path) -> anyhow::Result<Vec<(String, Record)>> {
+    let mut conversations = Vec::new();
+    let mut entries = tokio::fs::read_dir(db_path).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if !JsonStore::is_conversation(&path).await? {
+            continue;
+        }
+        let id = entry.file_name().to_string_lossy().to_string();
+        if validate_id(&id).is_err() {
+            continue;
+        }
+        match JsonStore::read(&path).await {
+            Ok(record) => conversations.push((id, record)),
+            Err(e) => warn!("Not importing {}: {}", path.display(), e),
+        }
+    }
+    Ok(conversations)
+}
+
+fn load(connection: &Connection, id: &str) -> rusqlite::Result<Option<Record>> {
+    let metadata = connection
+        .query_row(
+            "SELECT title, created_at, updated_at, model, forked_from, forked_at_turn
+             FROM conversations WHERE id = ?1",
+            [id],
+            |row| {
+                let forked_from = row.get::<_, Option<String>>(4)?;
+                let forked_at_turn = row.get::<_, Option<usize>>(5)?;
+                Ok(Metadata {
+                    title: row.get(0)?,
+                    created_at: row.get(1)?,
+                    updated_at: row.get(2)?,
+                    model: row.get(3)?,
+                    forked_from: forked_from
+                        .zip(forked_at_turn)
+                        .map(|(id, turn)| Fork { id, turn }),
+                })
+            },
+        )
+        .optional()?;
+    let Some(metadata) = metadata else {
+        return Ok(None);
+    };
+
+    let mut record = Record {
+        metadata,
+        messages: Vec::new(),
+        archive: Vec::new(),
+    };
+    let mut statement = connection.prepare_cached(
+        "SELECT archived, role, blocks, created_at FROM messages WHERE conversation_id = ?1
+         ORDER BY archived, position",
+    )?;
+    let mut rows = statement.query([id])?;
+    while let Some(row) = rows.next()? {
+        let blocks = row.get::<_, String>(2)?;
+        let message = StorableMessage {
+            role: row.get(1)?,
+            content: serde_json::from_str(&blocks)
+                .map_err(|e| rusqlite::Error::FromSqlConversionFailure(2, Type::Text, e.into()))?,
+            created_at: row.get(3)?,
+        };
+        if row.get(0)? {
+            record.archive.push(message);
+        } else {
+            record.messages.push(message);
+        }
+    }
+    Ok(Some(record))
+}
+
+/// Inserts or updates the metadata of conversation `id`, keeping when it was created.
+fn upsert_metadata(tx: &Transaction, id: &str, metadata: &Metadata) -> rusqlite::Result<()> {
+    tx.execute(
+        "INSERT INTO conversations
+             (id, title, created_at, updated_at, model, forked_from, forked_at_turn)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+         ON CONFLICT (id) DO UPDATE SET
+             title = excluded.title, updated_at = excluded.updated_at, model = excluded.model,
+             forked_from = excluded.forked_from, forked_at_turn = excluded.forked_at_turn",
+        params![
+            id,
+            metadata.title,
+            metadata.created_at,
+            metadata.updated_at,
+            metadata.model,
+            metadata.forked_from.as_ref().map(|fork| &fork.id),
+            metadata.forked_from.as_ref().map(|fork| fork.turn),
+        ],
+    )?;
+    Ok(())
+}
+
+fn insert_message(
+    tx: &Transaction,
+    id: &str,
+    archived: bool,
+    position: usize,
+    message: &StorableMessage,
+) -> rusqlite::Result<()> {
+    let blocks = serde_json::to_string(&message.content)
+        .map_err(|e| rusqlite::Error::ToSqlConversionFailure(e.into()))?;
+    tx.prepare_cached(
+        "INSERT INTO messages (conversation_id, archived, position, role, blocks, created_at)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+    )?
+    .execute(params![
+        id,
+        archived,
+        position,
+        message.role,
+        blocks,
+        message.created_at
+    ])?;
+    Ok(())
+}
+
+fn save(tx: &Transaction, id: &str, record: &Record) -> rusqlite::Result<()> {
+    upsert_metadata(tx, id, &record.metadata)?;
+    tx.execute("DELETE FROM messages WHERE conversation_id = ?1", [id])?;
+    for (position, message) in record.archive.iter().enumerate() {
+        insert_message(tx, id, true, position, message)?;
+    }
+    for (position, message) in record.messages.iter().enumerate() {
+        insert_message(tx, id, false, position, message)?;
+    }
+    Ok(())
+}
+
+#[async_trait::async_trait]
+impl ConversationStore for SqliteStore {
+    async fn load(&self, id: &str) -> anyhow::Result<Option<Record>> {
+        let id = id.to_string();
+        self.with(move |connection| load(connection, &id)).await
+    }
+
+    async fn list(&self) -> anyhow::Result<Vec<(String, Record)>> {
+        self.with(|connection| {
+            let ids = connection
+                .prepare("SELECT id FROM conversations ORDER BY updated_at DESC")?
+                .query_map([], |row| row.get::<_, String>(0))?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            let mut conversations = Vec::new();
+            for id in ids {
+                if let Some(record) = load(connection, &id)? {
+                    conversations.push((id, record));
+                }
+            }
+            Ok(conversations)
+        })
+        .await
+    }
+
+    async fn append(
+        &self,
+        id: &str,
+        metadata: &Metadata,
+        messages: &[StorableMessage],
+    ) -> anyhow::Result<()> {
+        let id = id.to_string();
+        let metadata = metadata.clone();
+        let messages = messages.to_vec();
+        self.with(move |connection| {
+            let tx = connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            upsert_metadata(&tx, &id, &metadata)?;
+            let next: usize = tx.query_row(
+                "SELECT COALESCE(MAX(position) + 1, 0) FROM messages
+                 WHERE conversation_id = ?1 AND archived = 0",
+                [&id],
+                |row| row.get(0),
+            )?;
+            for (i, message) in messages.iter().enumerate() {
+                insert_message(&tx, &id, false, next + i, message)?;
+            }
+            tx.commit()
+        })
+        .await
+    }
+
+    async fn save(&self, id: &str, record: &Record) -> anyhow::Result<()> {
+        let id = id.to_string();
+        let record = record.clone();
+        self.with(move |connection| {
+            let tx = connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            save(&tx, &id, &record)?;
+            tx.commit()
+        })
+        .await
+    }
+
+    async fn save_metadata(&self, id: &str, metadata: &Metadata) -> anyhow::Result<()> {
+        let id = id.to_string();
+        let metadata = metadata.clone();
+        let updated = self
+            .with(move |connection| {
+                connection.execute(
+                    "UPDATE conversations SET title = ?2, updated_at = ?3, model = ?4,
+                         forked_from = ?5, forked_at_turn = ?6
+                     WHERE id = ?1",
+                    params![
+                        id,
+                        metadata.title,
+                        metadata.updated_at,
+                        metadata.model,
+                        metadata.forked_from.as_ref().map(|fork| &fork.id),
+                        metadata.forked_from.as_ref().map(|fork| fork.turn),
+                    ],
+                )
+            })
+            .await?;
+        if updated == 0 {
+            anyhow::bail!("no conversation to update");
+        }
+        Ok(())
+    }
+
+    async fn lock(&self, id: &str) -> anyhow::Result<ConversationLock> {
+        ConversationLock::acquire(&self.db_path, id).await
+    }
+
+    async fn delete(&self, id: &str) -> anyhow::Result<()> {
+        let id = id.to_string();
+        let deleted = self
+            .with(move |connection| {
+                connection.execute("DELETE FROM conversations WHERE id = ?1", [&id])
+            })
+            .await?;
+        if deleted == 0 {
+            anyhow::bail!("no conversation to delete");
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_import_json() {
+        let dir = tempfile::tempdir().unwrap();
+        let messages = r#"[{"role":"user","content":"hi"},{"role":"assistant","content":"hello"}]"#;
+        tokio::fs::write(dir.path().join("1"), messages)
+            .await
+            .unwrap();
+        tokio::fs::write(dir.path().join("2"), "not json")
+            .await
+            .unwrap();
+
+        let store = SqliteStore::open(dir.path()).await.unwrap();
+        let record = store.load("1").await.unwrap().unwrap();
+        assert_eq!(record.messages.len(), 2);
+        assert!(store.load("2").await.unwrap().is_none());
+        assert!(dir.path().join(IMPORTED_DIR).join("1").is_file());
+        assert!(!dir.path().join("1").exists());
+        // Conversations that could not be imported are left alone.
+        assert!(dir.path().join("2").is_file());
+
+        // Opening it again does not import anything more.
+        tokio::fs::write(dir.path().join("3"), messages)
+            .await
+            .unwrap();
+        let store = SqliteStore::open(dir.path()).await.unwrap();
+        assert!(store.load("3").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_migrate_text_messages() {
+        let dir = tempfile::tempdir().unwrap();
+        let connection = Connection::open(dir.path().join(FILE_NAME)).unwrap();
+        for migration in &MIGRATIONS[..2] {
+            connection.execute_batch(migration).unwrap();
+        }
+        connection.pragma_update(None, "user_version", 2).unwrap();
+        connection
+            .execute_batch(
+                "INSERT INTO conversations (id, created_at, updated_at)
+                 VALUES ('1', '2024-12-01T00:00:00Z', '2024-12-01T00:00:00Z');
+                 INSERT INTO messages (conversation_id, archived, position, role, content)
+                 VALUES ('1', 0, 0, 'user', 'what is a \"trie\"');",
+            )
+            .unwrap();
+        drop(connection);
+
+        let store = SqliteStore::open(dir.path()).await.unwrap();
+        let record = store.load("1").await.unwrap().unwrap();
+        assert_eq!(
+            record.messages,
+            [StorableMessage::new("user", "what is a \"trie\"")]
+        );
+    }
+}
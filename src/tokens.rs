@@ -0,0 +1,166 @@
+//! Rough token accounting, to keep requests within the model's context window.
+//!
+//! Tokens are estimated rather than counted, since every provider tokenizes differently and none
+//! of them expose their tokenizer offline. Estimates err on the high side for English and code.
+
+use crate::message::ContentBlock;
+use crate::{ModelRequest, StorableMessage};
+
+/// Tokens kept free for the model's reply.
+pub const RESERVED_OUTPUT_TOKENS: usize = 4096;
+/// Tokens every message costs beyond its content, for its role and separators.
+const MESSAGE_OVERHEAD_TOKENS: usize = 4;
+/// The context window assumed for models that are not known.
+const DEFAULT_CONTEXT_WINDOW: usize = 8192;
+
+/// Context windows by model id prefix, most specific first. Prefixes also match any part of the id
+/// after a `.` or `/`, e.g. `claude` in `us.anthropic.claude-3-haiku`.
+const CONTEXT_WINDOWS: &[(&str, usize)] = &[
+    ("claude", 200_000),
+    ("gpt-4o", 128_000),
+    ("gpt-4.1", 1_000_000),
+    ("gpt-4-turbo", 128_000),
+    ("gpt-3.5", 16_385),
+    ("o1", 128_000),
+    ("o3", 200_000),
+    ("llama3", 128_000),
+    ("mistral", 32_000),
+    ("qwen", 32_000),
+];
+
+/// Tokens an attached image is assumed to cost, as its size is not known without decoding it.
+const IMAGE_TOKENS: usize = 1600;
+/// The most tokens an attached document is assumed to cost, however large the file. Most of a
+/// large PDF or spreadsheet is usually images, fonts or formatting rather than text.
+const MAX_DOCUMENT_TOKENS: usize = 16_000;
+
+/// Estimates the number of tokens in `text`, at roughly four characters per token.
+pub fn estimate(text: &str) -> usize {
+    text.chars().count().div_ceil(4)
+}
+
+/// Estimates the number of tokens `message` will take up, including its overhead.
+pub fn estimate_message(message: &StorableMessage) -> usize {
+    message.content.iter().map(estimate_block).sum::<usize>() + MESSAGE_OVERHEAD_TOKENS
+}
+
+/// Estimates the number of tokens `block` will take up. Plain text documents are counted as text,
+/// and others, whose text is compressed or mixed with markup, at a quarter of the rate of text, both
+/// up to `MAX_DOCUMENT_TOKENS`.
+pub fn estimate_block(block: &ContentBlock) -> usize {
+    match block {
+        ContentBlock::Image { .. } => IMAGE_TOKENS,
+        ContentBlock::Document { format, data, .. } if format.is_text() => {
+            data.len().div_ceil(4).min(MAX_DOCUMENT_TOKENS)
+        }
+        ContentBlock::Document { data, .. } => data.len().div_ceil(16).min(MAX_DOCUMENT_TOKENS),
+        block => estimate(&block.to_text()),
+    }
+}
+
+/// Estimates the number of tokens `request` will take up.
+pub fn estimate_request(request: &ModelRequest) -> usize {
+    estimate(&request.system_prompt) + request.messages.iter().map(estimate_message).sum::<usize>()
+}
+
+/// The number of tokens a request to `model_id` may use, leaving room for the reply.
+pub fn max_input_tokens(model_id: &str) -> usize {
+    let model_id = model_id.to_lowercase();
+    let parts = std::iter::once(0)
+        .chain(model_id.match_indices(['.', '/']).map(|(i, _)| i + 1))
+        .map(|i| &model_id[i..])
+        .collect::<Vec<_>>();
+    let window = CONTEXT_WINDOWS
+        .iter()
+        .find(|(name, _)| parts.iter().any(|part| part.starts_with(name)))
+        .map(|(_, window)| *window)
+        .unwrap_or(DEFAULT_CONTEXT_WINDOW);
+    window.saturating_sub(RESERVED_OUTPUT_TOKENS)
+}
+
+/// Drops the oldest turns of `request` until it fits in `max_tokens`.
+///
+/// Turns are dropped as user/assistant pairs so that the history still starts with a user
+/// message, and the last message, which is the one being responded to, is always kept. Returns
+/// the number of messages dropped.
+pub fn trim_history(request: &mut ModelRequest, max_tokens: usize) -> usize {
+    let mut total = estimate_request(request);
+    let mut dropped = 0;
+    while total > max_tokens && request.messages.len() - dropped > 1 {
+        total -= estimate_message(&request.messages[dropped]);
+        dropped += 1;
+        // Never leave an assistant message first.
+        while request.messages.len() - dropped > 1 && request.messages[dropped].role != "user" {
+            total -= estimate_message(&request.messages[dropped]);
+            dropped += 1;
+        }
+    }
+    request.messages.drain(..dropped);
+    dropped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(role: &str, content: &str) -> StorableMessage {
+        StorableMessage::new(role, content)
+    }
+
+    #[test]
+    fn test_max_input_tokens() {
+        assert_eq!(
+            max_input_tokens("anthropic.claude-3-haiku-20240307-v1:0"),
+            200_000 - RESERVED_OUTPUT_TOKENS
+        );
+        assert_eq!(
+            max_input_tokens("gpt-4o-mini"),
+            128_000 - RESERVED_OUTPUT_TOKENS
+        );
+        assert_eq!(
+            max_input_tokens("o1-mini"),
+            128_000 - RESERVED_OUTPUT_TOKENS
+        );
+        assert_eq!(
+            max_input_tokens("meta/llama3.2:latest"),
+            128_000 - RESERVED_OUTPUT_TOKENS
+        );
+        assert_eq!(
+            max_input_tokens("my-o1-model"),
+            DEFAULT_CONTEXT_WINDOW - RESERVED_OUTPUT_TOKENS
+        );
+        assert_eq!(
+            max_input_tokens("my-model"),
+            DEFAULT_CONTEXT_WINDOW - RESERVED_OUTPUT_TOKENS
+        );
+    }
+
+    #[test]
+    fn test_trim_history() {
+        let turn = "x".repeat(400);
+        let mut request = ModelRequest {
+            system_prompt: String::new(),
+            messages: vec![
+                message("user", &turn),
+                message("assistant", &turn),
+                message("user", &turn),
+                message("assistant", &turn),
+                message("user", "latest"),
+            ],
+            tools: None,
+        };
+
+        // Everything fits.
+        assert_eq!(trim_history(&mut request, 1000), 0);
+        assert_eq!(request.messages.len(), 5);
+
+        // Only the newest pair and the latest message fit.
+        assert_eq!(trim_history(&mut request, 300), 2);
+        assert_eq!(request.messages.len(), 3);
+        assert_eq!(request.messages[0].role, "user");
+
+        // The latest message is kept even when it alone is over budget.
+        assert_eq!(trim_history(&mut request, 1), 2);
+        assert_eq!(request.messages, vec![message("user", "latest")]);
+    }
+}
@@ -0,0 +1,279 @@
+//! Local tools the model can call during a chat, scoped to the current repository.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+/// Tool output beyond this many bytes is truncated, to keep it from filling the context window.
+const MAX_OUTPUT_BYTES: usize = 64 * 1024;
+const MAX_GREP_MATCHES: usize = 200;
+
+/// The description of a tool, as advertised to the model.
+#[derive(Debug, Clone)]
+pub struct ToolSpec {
+    pub name: &'static str,
+    pub description: &'static str,
+    /// A JSON schema for the tool's input.
+    pub input_schema: Value,
+}
+
+/// Runs tools on behalf of the model. Every path the model passes is resolved against `root`, and
+/// rejected if it escapes it.
+#[derive(Debug)]
+pub struct ToolBox {
+    root: PathBuf,
+}
+
+#[derive(Deserialize)]
+struct ReadFileInput {
+    path: String,
+    start_line: Option<usize>,
+    end_line: Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct ListDirInput {
+    #[serde(default)]
+    path: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct GrepInput {
+    pattern: String,
+    #[serde(default)]
+    path: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct GitDiffInput {
+    #[serde(default)]
+    staged: bool,
+    #[serde(default)]
+    path: Option<String>,
+}
+
+impl ToolBox {
+    pub fn new(root: &Path) -> anyhow::Result<Self> {
+        let root = root
+            .canonicalize()
+            .with_context(|| format!("unable to resolve {}", root.display()))?;
+        Ok(Self { root })
+    }
+
+    pub fn specs(&self) -> Vec<ToolSpec> {
+        vec![
+            ToolSpec {
+                name: "read_file",
+                description: "Read a file in the repository, optionally only the given 1-based, inclusive range of lines.",
+                input_schema: json!({
+                    "type": "object",
+                    "properties": {
+                        "path": { "type": "string", "description": "Path relative to the repository root." },
+                        "start_line": { "type": "integer" },
+                        "end_line": { "type": "integer" }
+                    },
+                    "required": ["path"]
+                }),
+            },
+            ToolSpec {
+                name: "list_dir",
+                description: "List the entries of a directory in the repository. Directories end with '/'.",
+                input_schema: json!({
+                    "type": "object",
+                    "properties": {
+                        "path": { "type": "string", "description": "Path relative to the repository root. Defaults to the root." }
+                    }
+                }),
+            },
+            ToolSpec {
+                name: "grep",
+                description: "Search files in the repository for a regular expression, skipping files ignored by git. Returns matches as path:line:text.",
+                input_schema: json!({
+                    "type": "object",
+                    "properties": {
+                        "pattern": { "type": "string", "description": "A regular expression." },
+                        "path": { "type": "string", "description": "A file or directory to limit the search to." }
+                    },
+                    "required": ["pattern"]
+                }),
+            },
+            ToolSpec {
+                name: "git_diff",
+                description: "Show the uncommitted changes in the repository, as output by git diff.",
+                input_schema: json!({
+                    "type": "object",
+                    "properties": {
+                        "staged": { "type": "boolean", "description": "Show staged rather than unstaged changes." },
+                        "path": { "type": "string", "description": "A file or directory to limit the diff to." }
+                    }
+                }),
+            },
+        ]
+    }
+
+    /// Runs the tool `name`. Errors are meant to be reported back to the model, which can often
+    /// recover from them, e.g. by correcting a path.
+    pub async fn run(&self, name: &str, input: Value) -> anyhow::Result<String> {
+        let output = match name {
+            "read_file" => self.read_file(serde_json::from_value(input)?).await?,
+            "list_dir" => self.list_dir(serde_json::from_value(input)?).await?,
+            "grep" => {
+                let input: GrepInput = serde_json::from_value(input)?;
+                let path = self.resolve(input.path.as_deref().unwrap_or("."))?;
+                let root = self.root.clone();
+                tokio::task::spawn_blocking(move || grep(&root, &path, &input.pattern)).await??
+            }
+            "git_diff" => self.git_diff(serde_json::from_value(input)?).await?,
+            name => anyhow::bail!("unknown tool: {}", name),
+        };
+        Ok(truncate(output))
+    }
+
+    /// Resolves `path` against the root, failing if it does not exist or lies outside of it.
+    fn resolve(&self, path: &str) -> anyhow::Result<PathBuf> {
+        let resolved = self
+            .root
+            .join(path)
+            .canonicalize()
+            .with_context(|| format!("{} does not exist", path))?;
+        if !resolved.starts_with(&self.root) {
+            anyhow::bail!("{} is outside of the repository", path);
+        }
+        Ok(resolved)
+    }
+
+    async fn read_file(&self, input: ReadFileInput) -> anyhow::Result<String> {
+        let path = self.resolve(&input.path)?;
+        let text = tokio::fs::read_to_string(&path)
+            .await
+            .with_context(|| format!("unable to read {}", input.path))?;
+        if input.start_line.is_none() && input.end_line.is_none() {
+            return Ok(text);
+        }
+        let start = input.start_line.unwrap_or(1).max(1);
+        let end = input.end_line.unwrap_or(usize::MAX);
+        Ok(text
+            .lines()
+            .enumerate()
+            .filter(|(i, _)| (start..=end).contains(&(i + 1)))
+            .map(|(_, line)| format!("{}\n", line))
+            .collect())
+    }
+
+    async fn list_dir(&self, input: ListDirInput) -> anyhow::Result<String> {
+        let path = self.resolve(input.path.as_deref().unwrap_or("."))?;
+        let mut entries = tokio::fs::read_dir(&path).await?;
+        let mut names = Vec::new();
+        while let Some(entry) = entries.next_entry().await? {
+            let mut name = entry.file_name().to_string_lossy().to_string();
+            if entry.file_type().await?.is_dir() {
+                name.push('/');
+            }
+            names.push(name);
+        }
+        names.sort();
+        Ok(names.join("\n"))
+    }
+
+    async fn git_diff(&self, input: GitDiffInput) -> anyhow::Result<String> {
+        let mut command = tokio::process::Command::new("git");
+        command.current_dir(&self.root).arg("diff");
+        if input.staged {
+            command.arg("--staged");
+        }
+        if let Some(path) = input.path {
+            command.arg("--").arg(self.resolve(&path)?);
+        }
+        let output = command.output().await.context("unable to run git")?;
+        if !output.status.success() {
+            anyhow::bail!("{}", String::from_utf8_lossy(&output.stderr));
+        }
+        let diff = String::from_utf8_lossy(&output.stdout).to_string();
+        Ok(if diff.is_empty() {
+            "No changes.".to_string()
+        } else {
+            diff
+        })
+    }
+}
+
+fn grep(root: &Path, path: &Path, pattern: &str) -> anyhow::Result<String> {
+    let regex = regex::Regex::new(pattern)?;
+    let mut matches = Vec::new();
+    for entry in ignore::WalkBuilder::new(path).build() {
+        let entry = entry?;
+        if !entry.file_type().is_some_and(|t| t.is_file()) {
+            continue;
+        }
+        // Binary and non-UTF-8 files are skipped.
+        let Ok(text) = std::fs::read_to_string(entry.path()) else {
+            continue;
+        };
+        let display = entry.path().strip_prefix(root).unwrap_or(entry.path());
+        for (i, line) in text.lines().enumerate() {
+            if regex.is_match(line) {
+                matches.push(format!("{}:{}:{}", display.display(), i + 1, line));
+                if matches.len() == MAX_GREP_MATCHES {
+                    matches.push(format!("(stopped after {} matches)", MAX_GREP_MATCHES));
+                    return Ok(matches.join("\n"));
+                }
+            }
+        }
+    }
+    Ok(if matches.is_empty() {
+        "No matches.".to_string()
+    } else {
+        matches.join("\n")
+    })
+}
+
+fn truncate(mut output: String) -> String {
+    if output.len() > MAX_OUTPUT_BYTES {
+        let mut end = MAX_OUTPUT_BYTES;
+        while !output.is_char_boundary(end) {
+            end -= 1;
+        }
+        output.truncate(end);
+        output.push_str("\n(output truncated)");
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_tools_are_scoped_to_root() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("src")).unwrap();
+        std::fs::write(dir.path().join("src/lib.rs"), "fn a() {}\nfn b() {}\n").unwrap();
+        let tools = ToolBox::new(dir.path()).unwrap();
+
+        let text = tools
+            .run(
+                "read_file",
+                json!({ "path": "src/lib.rs", "start_line": 2 }),
+            )
+            .await
+            .unwrap();
+        assert_eq!(text, "fn b() {}\n");
+
+        let entries = tools.run("list_dir", json!({})).await.unwrap();
+        assert_eq!(entries, "src/");
+
+        let matches = tools
+            .run("grep", json!({ "pattern": "fn b" }))
+            .await
+            .unwrap();
+        assert_eq!(matches, "src/lib.rs:2:fn b() {}");
+
+        assert!(tools
+            .run("read_file", json!({ "path": "../outside" }))
+            .await
+            .is_err());
+        assert!(tools.run("list_dir", json!({ "path": "/" })).await.is_err());
+    }
+}
@@ -0,0 +1,304 @@
+//! End-to-end tests of the `hackathon` binary, answered by the `replay` backend from the
+//! cassettes under `tests/fixtures` so that no network access is needed.
+
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+    process::{Command, Output, Stdio},
+};
+
+use serde_json::Value;
+
+fn fixture(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures")
+        .join(name)
+}
+
+/// Runs the binary inside `dir` with the given cassette, piping `stdin` to it.
+fn run(dir: &Path, cassette: &str, args: &[&str], stdin: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_hackathon"))
+        .current_dir(dir)
+        .args(["--backend", "replay", "--cassette"])
+        .arg(fixture(cassette))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(stdin.as_bytes())
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(
+        output.status.success(),
+        "hackathon failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    output
+}
+
+/// Exports conversation `id` from the store inside `dir`.
+fn export(dir: &Path, id: &str) -> Value {
+    let output = run(dir, "chat.json", &["conversations", "export", id], "");
+    serde_json::from_slice(&output.stdout).unwrap()
+}
+
+#[test]
+fn test_code() {
+    let dir = tempfile::tempdir().unwrap();
+    let output = run(
+        dir.path(),
+        "code.json",
+        &["code", "write", "tests"],
+        "fn add(x: f32, y: f32) -> f32 {\n    x + y\n}\n",
+    );
+
+    let response: Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(response["type"], "code");
+    let blocks = response["message"].as_array().unwrap();
+    assert_eq!(blocks.len(), 1);
+    assert_eq!(blocks[0]["language"], "rust");
+    let code = blocks[0]["code"].as_str().unwrap();
+    assert!(code.starts_with("fn add(x: f32, y: f32) -> f32 {\\n"));
+    assert!(code.contains("#[test]"));
+}
+
+#[test]
+fn test_chat_resumes_conversation() {
+    let dir = tempfile::tempdir().unwrap();
+    let chat = |prompt: &str| {
+        let output = run(
+            dir.path(),
+            "chat.json",
+            &["chat", "-c", ".", "-r", "1", prompt],
+            "",
+        );
+        String::from_utf8(output.stdout).unwrap()
+    };
+
+    assert!(chat("what is a prefix tree").starts_with("A prefix tree"));
+    assert!(chat("when should I use one").starts_with("Use one for autocomplete"));
+
+    let history = export(dir.path(), "1");
+    let roles = history["messages"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|m| m["role"].as_str().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(roles, vec!["user", "assistant", "user", "assistant"]);
+}
+
+#[test]
+fn test_chat_fork() {
+    let dir = tempfile::tempdir().unwrap();
+    let run = |args: &[&str]| {
+        let output = run(dir.path(), "chat.json", args, "");
+        String::from_utf8(output.stdout).unwrap()
+    };
+    run(&["chat", "-c", ".", "-r", "1", "what is a prefix tree"]);
+    run(&["chat", "-c", ".", "-r", "1", "when should I use one"]);
+
+    // Asks the second question again in a fork holding only the first turn.
+    let reply = run(&[
+        "chat",
+        "-c",
+        ".",
+        "-r",
+        "2",
+        "--fork-from",
+        "1@1",
+        "when should I use one",
+    ]);
+    assert!(reply.starts_with("Use one for autocomplete"));
+    assert_eq!(
+        export(dir.path(), "2")["messages"]
+            .as_array()
+            .unwrap()
+            .len(),
+        4
+    );
+    assert_eq!(
+        export(dir.path(), "1")["messages"]
+            .as_array()
+            .unwrap()
+            .len(),
+        4
+    );
+
+    let show = run(&["conversations", "show", "2"]);
+    assert!(show.contains("Forked from: 1 at turn 1\n"));
+    assert!(show.contains(
+        "\nForks:\n1  what is a prefix tree\n└── 2 (turn 1)  what is a prefix tree  *\n"
+    ));
+}
+
+#[test]
+fn test_conversations() {
+    let dir = tempfile::tempdir().unwrap();
+    let run = |args: &[&str]| {
+        let output = run(dir.path(), "chat.json", args, "");
+        String::from_utf8(output.stdout).unwrap()
+    };
+    run(&["chat", "-c", ".", "-r", "1", "what is a prefix tree"]);
+
+    let list: Value = serde_json::from_str(&run(&["conversations", "list", "--json"])).unwrap();
+    assert_eq!(list[0]["id"], "1");
+    assert_eq!(list[0]["title"], "what is a prefix tree");
+    assert_eq!(list[0]["message_count"], 2);
+    assert_eq!(list[0]["model"], "anthropic.claude-3-haiku-20240307-v1:0");
+
+    let matches: Value = serde_json::from_str(&run(&[
+        "conversations",
+        "search",
+        "--json",
+        "PREFIXES lookups",
+    ]))
+    .unwrap();
+    assert_eq!(matches.as_array().unwrap().len(), 1);
+    assert_eq!(matches[0]["id"], "1");
+    assert_eq!(matches[0]["turn"], 1);
+    assert_eq!(matches[0]["role"], "assistant");
+    assert!(run(&["conversations", "search", "no such words"]).is_empty());
+
+    run(&["conversations", "rename", "1", "tries"]);
+    assert!(run(&["conversations", "show", "1"]).starts_with("Title: tries\n"));
+    let export: Value = serde_json::from_str(&run(&["conversations", "export", "1"])).unwrap();
+    assert_eq!(export["metadata"]["title"], "tries");
+    assert_eq!(export["messages"][1]["role"], "assistant");
+    let sent_at = export["messages"][0]["created_at"].as_str().unwrap();
+    let sent_at = chrono::DateTime::parse_from_rfc3339(sent_at).unwrap();
+    let markdown = run(&["conversations", "export", "--format", "markdown", "1"]);
+    assert!(markdown.starts_with("# tries\n"));
+    assert!(markdown.contains(&format!(
+        "\n## User\n\n*{}*\n\nwhat is a prefix tree\n\n## Assistant\n",
+        sent_at.to_rfc3339()
+    )));
+
+    run(&["conversations", "delete", "1"]);
+    assert_eq!(run(&["conversations", "list"]), "");
+}
+
+#[test]
+fn test_code_ndjson() {
+    let dir = tempfile::tempdir().unwrap();
+    let output = run(
+        dir.path(),
+        "code.json",
+        &["code", "--output", "ndjson", "write", "tests"],
+        "fn add(x: f32, y: f32) -> f32 {\n    x + y\n}\n",
+    );
+
+    let events = String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str::<Value>(line).unwrap())
+        .collect::<Vec<_>>();
+    let types = events
+        .iter()
+        .map(|e| e["type"].as_str().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(types, vec!["start", "delta", "code_block", "done"]);
+    assert_eq!(events[0]["version"], 1);
+    assert_eq!(events[0]["kind"], "code");
+    assert_eq!(events[2]["language"], "rust");
+}
+
+#[test]
+fn test_serve() {
+    let dir = tempfile::tempdir().unwrap();
+    let requests = [
+        r#"{"jsonrpc":"2.0","id":1,"method":"chat/send","params":{"conversation_id":"1","prompt":"what is a prefix tree","stream":true}}"#,
+        r#"{"jsonrpc":"2.0","id":2,"method":"unknown/method"}"#,
+        "not json",
+    ];
+    let output = run(
+        dir.path(),
+        "chat.json",
+        &["serve", "--stdio"],
+        &(requests.join("\n") + "\n"),
+    );
+
+    let messages = String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str::<Value>(line).unwrap())
+        .collect::<Vec<_>>();
+    // Notifications have no id at all, unlike responses to unparseable requests.
+    let response = |id: Value| messages.iter().find(|m| m.get("id") == Some(&id)).unwrap();
+
+    assert!(response(1.into())["result"]["message"]
+        .as_str()
+        .unwrap()
+        .starts_with("A prefix tree"));
+    assert_eq!(response(2.into())["error"]["code"], -32601);
+    assert_eq!(response(Value::Null)["error"]["code"], -32700);
+    assert!(messages
+        .iter()
+        .any(|m| m["method"] == "delta" && m["params"]["request_id"] == 1));
+    assert_eq!(export(dir.path(), "1")["messages"][0]["role"], "user");
+}
+
+#[test]
+fn test_mcp() {
+    let dir = tempfile::tempdir().unwrap();
+    let requests = [
+        r#"{"jsonrpc":"2.0","id":1,"method":"initialize","params":{"protocolVersion":"2024-11-05","capabilities":{},"clientInfo":{"name":"test","version":"0"}}}"#,
+        r#"{"jsonrpc":"2.0","method":"notifications/initialized"}"#,
+        r#"{"jsonrpc":"2.0","id":2,"method":"tools/list"}"#,
+        r#"{"jsonrpc":"2.0","id":3,"method":"tools/call","params":{"name":"chat","arguments":{"prompt":"what is a prefix tree","conversation_id":"1"}}}"#,
+    ];
+    let output = run(
+        dir.path(),
+        "chat.json",
+        &["mcp"],
+        &(requests.join("\n") + "\n"),
+    );
+
+    let responses = String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str::<Value>(line).unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(responses.len(), 3);
+    assert_eq!(responses[0]["result"]["protocolVersion"], "2024-11-05");
+    let tools = responses[1]["result"]["tools"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|t| t["name"].as_str().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(tools, vec!["chat", "code"]);
+    assert_eq!(responses[2]["result"]["isError"], false);
+    assert!(responses[2]["result"]["content"][0]["text"]
+        .as_str()
+        .unwrap()
+        .starts_with("A prefix tree"));
+    assert_eq!(export(dir.path(), "1")["messages"][0]["role"], "user");
+}
+
+#[test]
+fn test_code_cursor_position() {
+    let dir = tempfile::tempdir().unwrap();
+    let output = run(
+        dir.path(),
+        "focused.json",
+        &["code", "-p", "6,5", "add a doc comment"],
+        "pub fn add(x: f32, y: f32) -> f32 {\n    x + y\n}\n\npub fn sub(x: f32, y: f32) -> f32 {\n    x - y\n}\n",
+    );
+
+    let response: Value = serde_json::from_slice(&output.stdout).unwrap();
+    let blocks = response["message"].as_array().unwrap();
+    assert_eq!(blocks.len(), 1);
+    assert_eq!(blocks[0]["range"]["start_line"], 5);
+    assert_eq!(blocks[0]["range"]["end_line"], 7);
+    assert!(blocks[0]["code"]
+        .as_str()
+        .unwrap()
+        .starts_with("/// Subtracts `y` from `x`.\\npub fn sub"));
+}